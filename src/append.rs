@@ -0,0 +1,273 @@
+//! Appending shelves to pressed files
+//!
+//! An appendable file is pressed as usual, followed by a footer pointing to
+//! the run table up front. Appending writes the new shelves after the end of
+//! the file, then a run table listing the runs already in the file followed by
+//! the new ones, then a new footer pointing to that table. Readers of
+//! appendable files take the run table from the footer at the very end, the
+//! earlier tables and footers stay behind unused.
+//!
+//! The footer holds the offset of the run table and its number of runs as
+//! little-endian `u64`s, then as `u32`s the required features of the whole
+//! file, since appended shelves may be compressed with a codec the header
+//! doesn't list, the checksum of the run table, the checksum of the footer
+//! fields so far and the footer magic.
+
+use std::{
+    fs::File,
+    io::{BufWriter, Seek, SeekFrom, Write},
+    mem::size_of,
+    ops::Range,
+};
+
+use crate::{hash, spool::ShelfWriter, to_usize, Collector, Decoder, Error, Features, Header, Reader, Result, RunDesc};
+
+/// Trailer of an appendable file, locating its latest run table
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Footer {
+    pub table_offset: usize,
+    pub runs: usize,
+    pub required_features: Features,
+    pub table_checksum: u32,
+}
+
+impl Footer {
+    pub const SIZE: usize = 2 * size_of::<u64>() + 4 * size_of::<u32>();
+    const MAGIC: u32 = 0x55AA55EE;
+    const FIELDS_SIZE: usize = Self::SIZE - 2 * size_of::<u32>();
+
+    /// Footer for the `runs` descriptors of `table`, placed at `table_offset`
+    pub fn new(table_offset: usize, runs: usize, required_features: Features, table: &[u8]) -> Self {
+        Self {
+            table_offset,
+            runs,
+            required_features,
+            table_checksum: hash::checksum(table),
+        }
+    }
+
+    pub fn write_out<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let mut fields = Vec::with_capacity(Self::SIZE);
+        fields.extend_from_slice(&(self.table_offset as u64).to_le_bytes());
+        fields.extend_from_slice(&(self.runs as u64).to_le_bytes());
+        fields.extend_from_slice(&self.required_features.bits().to_le_bytes());
+        fields.extend_from_slice(&self.table_checksum.to_le_bytes());
+        fields.extend_from_slice(&hash::checksum(&fields).to_le_bytes());
+        fields.extend_from_slice(&Self::MAGIC.to_le_bytes());
+        writer.write_all(&fields)?;
+        Ok(())
+    }
+
+    /// Parse and check the footer at the end of the file in `buf`, and the run table it points to
+    pub fn from_end(buf: &[u8], header: &Header) -> Result<Self> {
+        let start = buf.len().checked_sub(Self::SIZE).ok_or(Error::Truncated {
+            needed: Self::SIZE,
+            available: buf.len(),
+        })?;
+        let mut decoder = Decoder::new(&buf[start..]);
+        let table_offset = to_usize(decoder.u64()?)?;
+        let runs = to_usize(decoder.u64()?)?;
+        let required_features = Features::from_bits(decoder.u32()?);
+        let table_checksum = decoder.u32()?;
+        let checksum = decoder.u32()?;
+        if decoder.u32()? != Self::MAGIC {
+            return Err(Error::BadMagic);
+        }
+        if hash::checksum(&buf[start..start + Self::FIELDS_SIZE]) != checksum {
+            return Err(Error::FooterChecksum);
+        }
+        let unknown = required_features.difference(Features::KNOWN_REQUIRED);
+        if !unknown.is_empty() {
+            return Err(Error::UnsupportedFeatures(unknown.bits()));
+        }
+        let footer = Self {
+            table_offset,
+            runs,
+            required_features,
+            table_checksum,
+        };
+        let range = footer.table_range(header.version())?;
+        if range.start < header.size() || range.end > start {
+            return Err(format!("Run table at {}..{} lies outside the file's data", range.start, range.end).into());
+        }
+        if hash::checksum(&buf[range]) != table_checksum {
+            return Err(Error::RunTableChecksum);
+        }
+        Ok(footer)
+    }
+
+    fn table_range(&self, version: u32) -> Result<Range<usize>> {
+        let end = RunDesc::encoded_size(version)
+            .checked_mul(self.runs)
+            .and_then(|size| size.checked_add(self.table_offset))
+            .ok_or(Error::Overflow(self.runs as u64))?;
+        Ok(self.table_offset..end)
+    }
+}
+
+/// What [`Collector::append`] wrote
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AppendStats {
+    /// Bytes written
+    pub written: usize,
+    /// Blocks appended
+    pub blocks: usize,
+    /// Blocks the file held already, skipped with dedup
+    pub skipped: usize,
+}
+
+/// See [`Collector::append`]
+pub(crate) fn append(collector: &Collector, file: &mut File) -> Result<AppendStats> {
+    let reader = Reader::from_file(file)?;
+    let header = *reader.header();
+    if !header.required_features().contains(Features::APPENDABLE) {
+        return Err("The file wasn't pressed appendable".into());
+    }
+    if collector.is_empty() {
+        return Ok(AppendStats::default());
+    }
+    let expected = Header::for_collector(collector, 0);
+    let settings = |header: &Header| (header.version, header.max_size, header.seed, header.options, header.alignment, header.optional_features.bits());
+    if settings(&expected) != settings(&header) {
+        return Err(format!("The collector's settings {expected:?} don't match the file's {header:?}").into());
+    }
+    if let (Some(heap), Some(existing)) = (collector.heap.as_ref(), reader.heap()) {
+        if heap.block_size != existing.block_size() {
+            return Err(format!("Heap threshold {} doesn't match the file's {}", heap.block_size, existing.block_size()).into());
+        }
+    }
+
+    let mut stats = AppendStats::default();
+    let mut shelves = Vec::new();
+    for shelf in collector.runs() {
+        let mut writer = ShelfWriter::new(collector, shelf.kind, shelf.block_size, None)?;
+        for block in shelf.sorted_blocks() {
+            if collector.dedup && reader.contains(&block.data) {
+                // The file's value would shadow the new one, so updates can't be appended
+                if reader.get(&block.data).is_some_and(|value| value != block.value()) {
+                    return Err(format!("Key {:?} is in the file already with a different value", String::from_utf8_lossy(&block.data)).into());
+                }
+                stats.skipped += 1;
+                continue;
+            }
+            writer.push(block.hash, &block.data, block.value())?;
+            stats.blocks += 1;
+        }
+        shelves.extend(writer.finish()?);
+    }
+    if shelves.is_empty() {
+        return Ok(stats);
+    }
+    let version = header.version();
+    let mut required_features = header.required_features();
+    let mut table = Vec::new();
+    for desc in reader.runs() {
+        desc.write_out(&mut table, version)?;
+    }
+    let runs = reader.runs().len() + shelves.len();
+    let end = reader.file_size();
+    drop(reader);
+
+    let mut positions = Vec::with_capacity(shelves.len());
+    let mut offset = end;
+    for encoded in shelves.iter() {
+        let start = offset;
+        offset = offset.checked_next_multiple_of(collector.alignment).ok_or(Error::Overflow(offset as u64))?;
+        positions.push((start, offset));
+        encoded.run_desc(offset).write_out(&mut table, version)?;
+        offset = offset
+            .checked_add(encoded.bulk.len() + encoded.extension.len())
+            .ok_or(Error::Overflow(offset as u64))?;
+        if let Some(codec) = encoded.codec {
+            required_features = required_features.union(codec.feature());
+        }
+    }
+    let footer = Footer::new(offset, runs, required_features, &table);
+
+    file.seek(SeekFrom::Start(end as u64))?;
+    let mut writer = BufWriter::new(&mut *file);
+    for (encoded, (start, offset)) in shelves.iter().zip(positions.iter()) {
+        writer.write_all(&vec![0; offset - start])?;
+        writer.write_all(&encoded.bulk)?;
+        writer.write_all(&encoded.extension)?;
+    }
+    writer.write_all(&table)?;
+    footer.write_out(&mut writer)?;
+    writer.flush()?;
+    stats.written = offset + table.len() + Footer::SIZE - end;
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{union, CollectorBuilder};
+    use tempfile::tempfile;
+
+    fn key(n: usize) -> String {
+        "a".repeat(n % 25) + &n.to_string()
+    }
+
+    fn collect(builder: &CollectorBuilder, keys: impl Iterator<Item = usize>, tag: &str) -> Collector {
+        let mut collector = builder.clone().build().unwrap();
+        for n in keys {
+            collector.insert(key(n), format!("{tag}{n}")).unwrap();
+        }
+        collector
+    }
+
+    #[test]
+    fn appends_read_like_one_press() {
+        let builder = Collector::builder().appendable(true).heap_threshold(20).perfect_hash(true).alignment(8);
+        let mut file = tempfile().unwrap();
+        collect(&builder, 0..500, "a").press(&mut file).unwrap();
+        assert!(Reader::from_file(&file).unwrap().verify().is_ok());
+
+        // With dedup, blocks the file holds are skipped, and value updates refused
+        let stats = collect(&builder, 250..500, "a").append(&mut file).unwrap();
+        assert_eq!(stats, AppendStats { written: 0, blocks: 0, skipped: 250 });
+        let size = file.metadata().unwrap().len();
+        assert!(collect(&builder, 400..800, "b").append(&mut file).is_err());
+        assert_eq!(file.metadata().unwrap().len(), size);
+        assert_eq!(collect(&builder, 500..800, "b").append(&mut file).unwrap().blocks, 300);
+        let builder = CollectorBuilder::from_reader(&Reader::from_file(&file).unwrap()).unwrap();
+        let size = file.metadata().unwrap().len() as usize;
+        let stats = collect(&builder, 800..1000, "c").append(&mut file).unwrap();
+        assert_eq!((stats.blocks, stats.skipped), (200, 0));
+        let written = stats.written;
+        assert_eq!(file.metadata().unwrap().len() as usize, size + written);
+
+        let value = |n: usize| match n {
+            0..500 => format!("a{n}"),
+            500..800 => format!("b{n}"),
+            _ => format!("c{n}"),
+        };
+        let reader = Reader::from_file(&file).unwrap();
+        reader.verify().unwrap();
+        for n in 0..1100 {
+            assert_eq!(reader.get(key(n)).map(<[u8]>::to_vec), (n < 1000).then(|| value(n).into_bytes()), "{n}");
+        }
+
+        // Merged, the shelves of every append make up the file a single press gives
+        let mut merged = Vec::new();
+        union(std::slice::from_ref(&reader)).unwrap().press(&mut merged).unwrap();
+        let mut collector = builder.clone().appendable(false).build().unwrap();
+        for n in 0..1000 {
+            collector.insert(key(n), value(n)).unwrap();
+        }
+        let mut pressed = Vec::new();
+        collector.press(&mut pressed).unwrap();
+        assert!(merged == pressed);
+
+        // Settings have to match, and only appendable files take appends
+        assert!(collect(&builder.clone().seed(7), 0..1, "").append(&mut file).is_err());
+        assert!(collect(&builder.clone().heap_threshold(30), 0..1, "").append(&mut file).is_err());
+        let mut plain = tempfile().unwrap();
+        collector.press(&mut plain).unwrap();
+        assert!(collect(&builder.clone().appendable(false), 2000..2001, "").append(&mut plain).is_err());
+
+        // A torn append leaves no footer at the end
+        file.set_len((size + written - 1) as u64).unwrap();
+        assert!(Reader::from_file(&file).is_err());
+    }
+}
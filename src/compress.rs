@@ -0,0 +1,324 @@
+//! Chunked compression of bulk regions
+//!
+//! A compressed run splits its entries into chunks of a fixed number of
+//! entries. Each chunk is encoded exactly like an uncompressed bulk region
+//! holding just its entries, then compressed on its own, and the compressed
+//! chunks are laid out back to back in the run's bulk region. The chunk index
+//! in the run's extension region locates every chunk and records the hash of
+//! its first entry, so lookups pick a chunk without decompressing any.
+//!
+//! The chunk index encoding is the codec as a little-endian `u32`, four zero
+//! bytes, the entries per chunk and the chunk count as `u64`s, then per chunk
+//! its offset in the bulk region, compressed and decompressed length as `u64`s
+//! and the first entry's hash as a `u32`.
+
+use std::{io::Write, mem::size_of};
+
+use crate::{Block, Compression, Decoder, Error, Features, Header, HeapEntry, Result, RunDesc, RunKind};
+
+const FIELDS_SIZE: usize = 2 * size_of::<u32>() + 2 * size_of::<u64>();
+const CHUNK_SIZE: usize = 3 * size_of::<u64>() + size_of::<u32>();
+
+/// Compression codec of a shelf, each needs its cargo feature enabled
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[repr(u32)]
+pub enum Codec {
+    /// LZ4 block format, cargo feature `lz4`
+    Lz4 = 1,
+    /// Zstandard, cargo feature `zstd`
+    Zstd = 2,
+}
+
+impl TryFrom<u32> for Codec {
+    type Error = Error;
+    fn try_from(value: u32) -> Result<Codec> {
+        match value {
+            1 => Ok(Codec::Lz4),
+            2 => Ok(Codec::Zstd),
+            other => Err(format!("Unknown codec {other}").into()),
+        }
+    }
+}
+
+impl Codec {
+    /// Required feature of files with shelves compressed by this codec
+    pub fn feature(&self) -> Features {
+        match self {
+            Codec::Lz4 => Features::LZ4,
+            Codec::Zstd => Features::ZSTD,
+        }
+    }
+
+    /// Whether this build can compress and decompress with the codec
+    pub fn is_available(&self) -> bool {
+        Features::KNOWN_REQUIRED.contains(self.feature())
+    }
+
+    /// Error for using the codec in a build without it
+    pub(crate) fn unavailable(&self) -> Error {
+        Error::UnsupportedFeatures(self.feature().bits())
+    }
+
+    #[cfg_attr(not(all(feature = "lz4", feature = "zstd")), allow(unused_variables))]
+    pub(crate) fn compress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            #[cfg(feature = "lz4")]
+            Codec::Lz4 => lz4::compress(data),
+            #[cfg(feature = "zstd")]
+            Codec::Zstd => zstd::compress(data),
+            #[cfg(not(feature = "lz4"))]
+            Codec::Lz4 => Err(self.unavailable()),
+            #[cfg(not(feature = "zstd"))]
+            Codec::Zstd => Err(self.unavailable()),
+        }
+    }
+
+    pub(crate) fn decompress(&self, data: &[u8], size: usize) -> Result<Vec<u8>> {
+        let decompressed = self.decompress_unchecked(data, size)?;
+        if decompressed.len() != size {
+            return Err(format!("Chunk decompressed to {} bytes instead of {size}", decompressed.len()).into());
+        }
+        Ok(decompressed)
+    }
+
+    #[cfg_attr(not(all(feature = "lz4", feature = "zstd")), allow(unused_variables))]
+    fn decompress_unchecked(&self, data: &[u8], size: usize) -> Result<Vec<u8>> {
+        match self {
+            #[cfg(feature = "lz4")]
+            Codec::Lz4 => lz4::decompress(data, size),
+            #[cfg(feature = "zstd")]
+            Codec::Zstd => zstd::decompress(data, size),
+            #[cfg(not(feature = "lz4"))]
+            Codec::Lz4 => Err(self.unavailable()),
+            #[cfg(not(feature = "zstd"))]
+            Codec::Zstd => Err(self.unavailable()),
+        }
+    }
+}
+
+#[cfg(feature = "lz4")]
+mod lz4 {
+    use crate::Result;
+
+    pub fn compress(data: &[u8]) -> Result<Vec<u8>> {
+        Ok(lz4_flex::block::compress(data))
+    }
+
+    pub fn decompress(data: &[u8], size: usize) -> Result<Vec<u8>> {
+        lz4_flex::block::decompress(data, size).map_err(|err| format!("Corrupt LZ4 chunk: {err}").into())
+    }
+}
+
+#[cfg(feature = "zstd")]
+mod zstd {
+    use crate::Result;
+
+    pub fn compress(data: &[u8]) -> Result<Vec<u8>> {
+        Ok(::zstd::bulk::compress(data, 0)?)
+    }
+
+    pub fn decompress(data: &[u8], size: usize) -> Result<Vec<u8>> {
+        Ok(::zstd::bulk::decompress(data, size)?)
+    }
+}
+
+/// Location of one compressed chunk
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Chunk {
+    pub offset: u64,
+    pub length: u64,
+    pub size: u64,
+    pub first_hash: u32,
+}
+
+/// Compress the encoded bulk region of a run of `count` entries
+///
+/// Writes the chunks out back to back and returns their total size and the
+/// encoded chunk index.
+pub(crate) fn compress_run<W: Write>(
+    kind: RunKind,
+    count: usize,
+    bulk: &[u8],
+    compression: Compression,
+    version: u32,
+    writer: &mut W,
+) -> Result<(u64, Vec<u8>)> {
+    // Every chunk holds the same number of entries, sized for the average entry
+    let average = bulk.len().div_ceil(count.max(1)).max(1);
+    let chunk_entries = compression.chunk_entries.unwrap_or(compression.chunk_size / average).max(1);
+    let mut chunks = Vec::new();
+    let mut offset = 0u64;
+    let mut chunk = Vec::new();
+    for start in (0..count).step_by(chunk_entries) {
+        let end = count.min(start + chunk_entries);
+        chunk.clear();
+        let first_hash = match kind {
+            RunKind::Fixed => {
+                let stride = bulk.len() / count;
+                chunk.extend_from_slice(&bulk[start * stride..end * stride]);
+                Block::read_hash(&chunk)
+            }
+            RunKind::Heap => {
+                // The chunk's entries point into its own share of the data
+                let stride = HeapEntry::encoded_size(version);
+                let data = &bulk[count * stride..];
+                let entries = (start..end)
+                    .map(|index| HeapEntry::from_buf(&bulk[index * stride..], version))
+                    .collect::<Result<Vec<_>>>()?;
+                let base = entries[0].offset;
+                let mut data_end = base;
+                for entry in entries.iter() {
+                    HeapEntry { offset: entry.offset - base, ..*entry }.write_out(&mut chunk, version)?;
+                    data_end = entry.offset + entry.length;
+                }
+                chunk.extend_from_slice(&data[base as usize..data_end as usize]);
+                Some(entries[0].hash)
+            }
+        };
+        let first_hash = first_hash.ok_or("Chunk without entries")?;
+        let compressed = compression.codec.compress(&chunk)?;
+        writer.write_all(&compressed)?;
+        chunks.push(Chunk {
+            offset,
+            length: compressed.len() as u64,
+            size: chunk.len() as u64,
+            first_hash,
+        });
+        offset += compressed.len() as u64;
+    }
+    Ok((offset, encode_index(compression.codec, chunk_entries, &chunks)))
+}
+
+/// Encode a chunk index
+pub(crate) fn encode_index(codec: Codec, chunk_entries: usize, chunks: &[Chunk]) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(FIELDS_SIZE + chunks.len() * CHUNK_SIZE);
+    payload.extend_from_slice(&(codec as u32).to_le_bytes());
+    payload.extend_from_slice(&0u32.to_le_bytes());
+    payload.extend_from_slice(&(chunk_entries as u64).to_le_bytes());
+    payload.extend_from_slice(&(chunks.len() as u64).to_le_bytes());
+    for chunk in chunks {
+        payload.extend_from_slice(&chunk.offset.to_le_bytes());
+        payload.extend_from_slice(&chunk.length.to_le_bytes());
+        payload.extend_from_slice(&chunk.size.to_le_bytes());
+        payload.extend_from_slice(&chunk.first_hash.to_le_bytes());
+    }
+    payload
+}
+
+/// Chunk index of a compressed run, parsed and checked once when the file is opened
+#[derive(Clone, Debug)]
+pub(crate) struct ChunkIndex {
+    codec: Codec,
+    chunk_entries: usize,
+    chunks: Vec<Chunk>,
+}
+
+impl ChunkIndex {
+    /// Parse the index of the compressed run `desc` in the file with `header`
+    ///
+    /// The chunks have to fit the bulk region and decompress to as many bytes
+    /// as their entries take: exactly for fixed runs, for heap runs at least
+    /// their index entries and at most one block of the header's maximum size
+    /// more per entry, so a corrupt size can't blow up decompression. The
+    /// descriptor's count and block size aren't checked against anything else.
+    pub fn new(payload: &[u8], desc: &RunDesc, header: &Header) -> Result<Self> {
+        let (count, bulk_size) = (desc.count, desc.length);
+        let mut decoder = Decoder::new(payload);
+        let codec = Codec::try_from(decoder.u32()?)?;
+        decoder.u32()?;
+        let chunk_entries = decoder.u64()?;
+        let chunk_count = decoder.u64()?;
+        let chunk_entries = usize::try_from(chunk_entries)
+            .ok()
+            .filter(|entries| *entries > 0)
+            .ok_or(Error::Overflow(chunk_entries))?;
+        if chunk_count != count.div_ceil(chunk_entries as u64) {
+            return Err(format!("{chunk_count} chunks of {chunk_entries} entries can't hold {count} entries").into());
+        }
+        let index_size = usize::try_from(chunk_count)
+            .ok()
+            .and_then(|chunks| chunks.checked_mul(CHUNK_SIZE))
+            .ok_or(Error::Overflow(chunk_count))?;
+        let mut decoder = Decoder::new(decoder.take(index_size)?);
+        // Fixed entries all take the same room, heap entries their index entry and a block
+        let (entry_size, max_entry_size) = match desc.kind() {
+            RunKind::Fixed => {
+                let size = desc.block_size.checked_add(Block::HASH_SIZE as u64).ok_or(Error::Overflow(desc.block_size))?;
+                (size, size)
+            }
+            RunKind::Heap => {
+                let size = HeapEntry::encoded_size(header.version()) as u64;
+                (size, size.checked_add(header.max_size()).ok_or(Error::Overflow(header.max_size()))?)
+            }
+        };
+        let mut chunks = Vec::with_capacity(index_size / CHUNK_SIZE);
+        for number in 0..chunk_count {
+            let chunk = Chunk {
+                offset: decoder.u64()?,
+                length: decoder.u64()?,
+                size: decoder.u64()?,
+                first_hash: decoder.u32()?,
+            };
+            let end = chunk.offset.checked_add(chunk.length).ok_or(Error::Overflow(chunk.offset))?;
+            if end > bulk_size {
+                return Err(format!("Chunk ending at {end} overruns its bulk region ({bulk_size} bytes)").into());
+            }
+            // Every chunk but the last is full
+            let entries = (count - number * chunk_entries as u64).min(chunk_entries as u64);
+            let min = entry_size.checked_mul(entries).ok_or(Error::Overflow(entries))?;
+            let max = max_entry_size.checked_mul(entries).ok_or(Error::Overflow(entries))?;
+            if chunk.size < min || chunk.size > max {
+                return Err(format!("Chunk {number} decompressing to {} bytes can't hold {entries} entries", chunk.size).into());
+            }
+            chunks.push(chunk);
+        }
+        Ok(Self {
+            codec,
+            chunk_entries,
+            chunks,
+        })
+    }
+
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    /// Entries in every chunk but possibly the last
+    pub fn chunk_entries(&self) -> usize {
+        self.chunk_entries
+    }
+
+    pub fn chunk(&self, index: usize) -> Option<Chunk> {
+        self.chunks.get(index).copied()
+    }
+
+    /// Number of chunks whose first entry's hash is below `hash`, a binary search as chunks come sorted by it
+    pub fn chunks_below(&self, hash: u32) -> usize {
+        self.chunks.partition_point(|chunk| chunk.first_hash < hash)
+    }
+
+    /// Decompress chunk `index` out of the run's `bulk` region
+    pub fn decompress(&self, bulk: &[u8], index: usize) -> Result<Vec<u8>> {
+        let chunk = self.chunk(index).ok_or_else(|| format!("No chunk {index}"))?;
+        // Bounds were checked by ChunkIndex::new
+        let data = &bulk[chunk.offset as usize..(chunk.offset + chunk.length) as usize];
+        let size = usize::try_from(chunk.size).map_err(|_| Error::Overflow(chunk.size))?;
+        self.codec.decompress(data, size)
+    }
+}
+
+#[cfg(all(test, any(feature = "lz4", feature = "zstd")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codecs_round_trip() {
+        let data: Vec<u8> = (0..10_000u32).flat_map(|n| (n % 100).to_le_bytes()).collect();
+        for codec in [Codec::Lz4, Codec::Zstd].into_iter().filter(Codec::is_available) {
+            let compressed = codec.compress(&data).unwrap();
+            assert!(compressed.len() < data.len() / 4);
+            assert_eq!(codec.decompress(&compressed, data.len()).unwrap(), data);
+            assert!(codec.decompress(&compressed, data.len() + 1).is_err());
+        }
+    }
+}
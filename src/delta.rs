@@ -0,0 +1,696 @@
+//! Delta files between two pressed files
+//!
+//! [`diff`] walks the shelves of an old and a new file side by side and
+//! writes out the blocks each shelf of the new file lost and gained, along
+//! with the settings the new file was pressed with. [`apply`] merges those
+//! changes into the shelves of the old file and presses the result, which is
+//! the new file byte for byte. Shelves are keyed as in the new file, blocks
+//! the old file kept elsewhere under another heap threshold move over.
+//!
+//! A delta file starts with its header: the magic, the delta format version,
+//! the number of runs, the size and head checksum of the old and of the new
+//! file, then the new file's format version, maximum block size, seed,
+//! options, alignment and optional features, its heap threshold plus one, zero
+//! without a heap, and its filter false positive rate as `f64` bits. The
+//! header ends with the checksum of its other fields and the run table
+//! following it. A run descriptor holds the kind of the new shelf, its codec,
+//! zero if uncompressed, its block size and entries per chunk, the number of
+//! blocks removed and added, and the offset, length and checksum of its
+//! changes. These are records of the removed blocks, then of the added ones,
+//! each in on-disk order, the block length as a `u64`, the value length plus
+//! one as a `u64`, zero for no value, then the block and value bytes. All
+//! fields are little-endian.
+//!
+//! A file's head checksum covers its header and run table, which hold the
+//! checksums of everything else, so it tells whether [`apply`] is given the
+//! right old file and whether its output is the new one. Both are checked
+//! before anything is written.
+
+use memmap::Mmap;
+use std::{
+    cmp::Ordering,
+    collections::BTreeSet,
+    fmt::Debug,
+    fs::File,
+    io::{self, BufWriter, Write},
+    mem::size_of,
+    path::Path,
+};
+
+use crate::{
+    hash,
+    merge::{shelf_entries, Entry, Merge, Route},
+    spool::{self, ShelfWriter},
+    to_usize, Codec, Collector, Compression, Decoder, Error, Features, Header, Reader, Region, Result, RunKind,
+};
+
+const DELTA_MAGIC: u32 = 0x55AA44DD;
+/// Delta format version written by [`diff`]
+const DELTA_VERSION: u32 = 1;
+/// Encoded size of the header fields before its checksum
+const HEADER_FIELDS_SIZE: usize = 9 * size_of::<u32>() + 6 * size_of::<u64>();
+const HEADER_SIZE: usize = HEADER_FIELDS_SIZE + size_of::<u32>();
+const RUN_SIZE: usize = 3 * size_of::<u32>() + 6 * size_of::<u64>();
+/// Size of the fixed fields of a change record
+const RECORD_FIELDS_SIZE: usize = 2 * size_of::<u64>();
+
+/// What [`diff`] wrote
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DiffStats {
+    /// Bytes written
+    pub written: usize,
+    /// Blocks of the old file missing from the new one
+    pub removed: usize,
+    /// Blocks of the new file missing from the old one
+    pub added: usize,
+}
+
+/// Write a delta turning `old` into `new`, returning what was written
+///
+/// Both files must be hashed with the same seed. A block whose value changed
+/// counts as removed and added again. The new file can't be appendable, its
+/// layout depends on the appends it went through.
+pub fn diff<W: Write>(old: &Reader, new: &Reader, writer: &mut W) -> Result<DiffStats> {
+    if old.seed() != new.seed() {
+        return Err(format!("Files hashed with different seeds {:#x} and {:#x}", old.seed(), new.seed()).into());
+    }
+    if new.header().required_features().contains(Features::APPENDABLE) {
+        return Err("Appendable files can't be reproduced from a delta".into());
+    }
+    let settings = Settings::of(new);
+    let route = settings.route();
+    let mut keys = BTreeSet::new();
+    keys.extend(new.shelves().map(|shelf| (shelf.kind(), shelf.block_size())));
+    for shelf in old.shelves() {
+        match shelf.kind() {
+            RunKind::Fixed => {
+                keys.insert(route.shelf_for(shelf.block_size()));
+            }
+            // Under a higher threshold the heap spreads over fixed shelves
+            RunKind::Heap if settings.heap_threshold.is_some_and(|threshold| threshold <= shelf.block_size()) => {
+                keys.insert(route.shelf_for(shelf.block_size()));
+            }
+            RunKind::Heap => keys.extend(shelf.blocks().map(|block| route.shelf_for(block.len()))),
+        }
+    }
+
+    let mut changes = BufWriter::new(spool::temp_file(None)?);
+    let mut runs = Vec::with_capacity(keys.len());
+    let mut offset = HEADER_SIZE + keys.len() * RUN_SIZE;
+    let (mut removed, mut added) = (0, 0);
+    for (kind, block_size) in keys {
+        let new_shelf = new.shelves().find(|shelf| (shelf.kind(), shelf.block_size()) == (kind, block_size));
+        let new_entries = new_shelf.into_iter().flat_map(shelf_entries);
+        // Added records go after the removed ones, spool them until the shelf is done
+        let mut additions = BufWriter::new(spool::temp_file(None)?);
+        let mut run = DeltaRun {
+            kind,
+            codec: new_shelf.and_then(|shelf| shelf.codec()),
+            block_size,
+            chunk_entries: new_shelf.and_then(|shelf| shelf.chunk_entries()).unwrap_or_default(),
+            removed: 0,
+            added: 0,
+            offset,
+            length: 0,
+            checksum: 0,
+        };
+        compare(old_entries(old, &settings, (kind, block_size))?, new_entries, |change, entry| {
+            match change {
+                Change::Removed => {
+                    run.removed += 1;
+                    run.length += write_record(&mut changes, entry)?;
+                }
+                Change::Added => {
+                    run.added += 1;
+                    run.length += write_record(&mut additions, entry)?;
+                }
+            }
+            Ok(())
+        })?;
+        io::copy(&mut spool::into_file(additions)?, &mut changes)?;
+        offset += run.length;
+        removed += run.removed;
+        added += run.added;
+        runs.push(run);
+    }
+
+    let changes = Region::from_file(&spool::into_file(changes)?)?;
+    let mut start = 0;
+    for run in runs.iter_mut() {
+        run.checksum = hash::checksum(&changes[start..start + run.length]);
+        start += run.length;
+    }
+    let header = DeltaHeader {
+        old: (old.file_size() as u64, old.head_checksum()),
+        new: (new.file_size() as u64, new.head_checksum()),
+        settings,
+    };
+    let head = header.encode(&runs)?;
+    writer.write_all(&head)?;
+    writer.write_all(&changes)?;
+    Ok(DiffStats {
+        written: head.len() + changes.len(),
+        removed,
+        added,
+    })
+}
+
+/// Press the file `delta` was made from `old` to, returning the number of bytes written
+pub fn apply<W: Write>(old: &Reader, delta: &Delta, writer: &mut W) -> Result<usize> {
+    let header = &delta.header;
+    if (old.file_size() as u64, old.head_checksum()) != header.old {
+        return Err("The delta was made against a different old file".into());
+    }
+    if old.seed() != header.settings.seed {
+        return Err(format!("The delta is for files hashed with seed {:#x}, not {:#x}", header.settings.seed, old.seed()).into());
+    }
+    let mut collector = header.settings.collector()?;
+    let mut shelves = Vec::new();
+    for (index, run) in delta.runs.iter().enumerate() {
+        let (removed, added) = split_changes(delta.changes(index)?, run.removed)?;
+        let seed = header.settings.seed;
+        // The shelf is compressed exactly like the one in the new file, or not at all
+        collector.compression = run.codec.map(|codec| Compression {
+            codec,
+            chunk_size: Compression::DEFAULT_CHUNK_SIZE,
+            chunk_entries: Some(run.chunk_entries),
+        });
+        let mut shelf = ShelfWriter::new(&collector, run.kind, run.block_size, None)?;
+        patch(
+            old_entries(old, &header.settings, (run.kind, run.block_size))?,
+            Records::new(removed, seed),
+            Records::new(added, seed),
+            |entry| shelf.push(entry.hash, &entry.data, entry.value.as_deref().unwrap_or_default()),
+        )?;
+        shelves.extend(shelf.finish()?);
+    }
+    collector.compression = None;
+    let layout = collector.layout(&shelves)?;
+    if (layout.size as u64, hash::checksum(&layout.head)) != header.new {
+        return Err("Applying the delta doesn't reproduce the new file".into());
+    }
+    Collector::write_layout(&layout, &shelves, writer)
+}
+
+/// Read-only view of a delta file written by [`diff`]
+pub struct Delta {
+    map: Mmap,
+    header: DeltaHeader,
+    runs: Vec<DeltaRun>,
+}
+
+impl Debug for Delta {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Delta")
+            .field("shelves", &self.runs.len())
+            .field("removed", &self.removed())
+            .field("added", &self.added())
+            .finish()
+    }
+}
+
+impl Delta {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = File::open(path)?;
+        Self::from_file(&file)
+    }
+
+    pub fn from_file(file: &File) -> Result<Self> {
+        // Sound for the reason given in Reader::from_file
+        let map = unsafe { Mmap::map(file) }?;
+        Self::from_map(map)
+    }
+
+    pub fn from_map(map: Mmap) -> Result<Self> {
+        let (header, runs) = DeltaHeader::decode(&map)?;
+        Ok(Self { map, header, runs })
+    }
+
+    /// Blocks of the old file missing from the new one
+    pub fn removed(&self) -> usize {
+        self.runs.iter().map(|run| run.removed).sum()
+    }
+
+    /// Blocks of the new file missing from the old one
+    pub fn added(&self) -> usize {
+        self.runs.iter().map(|run| run.added).sum()
+    }
+
+    /// Size of the file the delta reproduces
+    pub fn new_size(&self) -> u64 {
+        self.header.new.0
+    }
+
+    /// Checked change records of run `index`
+    fn changes(&self, index: usize) -> Result<&[u8]> {
+        let run = &self.runs[index];
+        let changes = &self.map[run.offset..run.offset + run.length];
+        if hash::checksum(changes) != run.checksum {
+            return Err(Error::BulkChecksum {
+                run: index,
+                block_size: run.block_size,
+            });
+        }
+        Ok(changes)
+    }
+}
+
+/// Settings of a pressed file, as far as pressing it again needs them
+#[derive(Clone, Copy, Debug)]
+struct Settings {
+    version: u32,
+    max_size: u64,
+    seed: u32,
+    options: u32,
+    alignment: u32,
+    features: Features,
+    heap_threshold: Option<usize>,
+    filter_rate: f64,
+}
+
+impl Settings {
+    fn of(reader: &Reader) -> Self {
+        let header = reader.header();
+        Self {
+            version: header.version(),
+            max_size: header.max_size(),
+            seed: header.seed(),
+            options: header.options,
+            alignment: header.alignment,
+            features: header.optional_features(),
+            heap_threshold: reader.heap().map(|heap| heap.block_size()),
+            filter_rate: reader.shelves().find_map(|shelf| shelf.filter_rate()).unwrap_or_default(),
+        }
+    }
+
+    /// Empty collector pressing with these settings, without compression
+    fn collector(&self) -> Result<Collector> {
+        let mut builder = Collector::builder()
+            .version(self.version)
+            .max_size(to_usize(self.max_size)?)
+            .seed(self.seed)
+            .dedup(self.options & Header::OPTION_DEDUP != 0)
+            .alignment(self.alignment as usize)
+            .perfect_hash(self.features.contains(Features::PERFECT_HASH));
+        if let Some(threshold) = self.heap_threshold {
+            builder = builder.heap_threshold(threshold);
+        }
+        let mut collector = builder.build()?;
+        collector.values = self.features.contains(Features::VALUES);
+        collector.filter = self.features.contains(Features::FILTER).then_some(self.filter_rate);
+        Ok(collector)
+    }
+
+    /// Routing of blocks to the shelves of a file with these settings
+    fn route(&self) -> Route {
+        Route(self.heap_threshold)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct DeltaHeader {
+    // Size and head checksum of the old and the new file
+    old: (u64, u32),
+    new: (u64, u32),
+    // Settings of the new file
+    settings: Settings,
+}
+
+impl DeltaHeader {
+    /// Encoded header and run table
+    fn encode(&self, runs: &[DeltaRun]) -> Result<Vec<u8>> {
+        let settings = &self.settings;
+        let mut head = Vec::with_capacity(HEADER_SIZE + runs.len() * RUN_SIZE);
+        head.extend_from_slice(&DELTA_MAGIC.to_le_bytes());
+        head.extend_from_slice(&DELTA_VERSION.to_le_bytes());
+        head.extend_from_slice(&(runs.len() as u64).to_le_bytes());
+        head.extend_from_slice(&self.old.0.to_le_bytes());
+        head.extend_from_slice(&self.old.1.to_le_bytes());
+        head.extend_from_slice(&self.new.0.to_le_bytes());
+        head.extend_from_slice(&self.new.1.to_le_bytes());
+        head.extend_from_slice(&settings.version.to_le_bytes());
+        head.extend_from_slice(&settings.max_size.to_le_bytes());
+        head.extend_from_slice(&settings.seed.to_le_bytes());
+        head.extend_from_slice(&settings.options.to_le_bytes());
+        head.extend_from_slice(&settings.alignment.to_le_bytes());
+        head.extend_from_slice(&settings.features.bits().to_le_bytes());
+        let heap_threshold = settings.heap_threshold.map_or(0, |threshold| threshold as u64 + 1);
+        head.extend_from_slice(&heap_threshold.to_le_bytes());
+        head.extend_from_slice(&settings.filter_rate.to_bits().to_le_bytes());
+        let mut table = Vec::with_capacity(runs.len() * RUN_SIZE);
+        for run in runs {
+            run.write_out(&mut table)?;
+        }
+        head.extend_from_slice(&hash::checksum(&[&head[..], &table].concat()).to_le_bytes());
+        head.extend_from_slice(&table);
+        Ok(head)
+    }
+
+    /// Parse and check the header and run table at the start of `buf`
+    fn decode(buf: &[u8]) -> Result<(Self, Vec<DeltaRun>)> {
+        let mut decoder = Decoder::new(buf);
+        if decoder.u32()? != DELTA_MAGIC {
+            return Err(Error::BadMagic);
+        }
+        if decoder.u32()? != DELTA_VERSION {
+            return Err(Error::InvalidVersion);
+        }
+        let run_count = to_usize(decoder.u64()?)?;
+        let old = (decoder.u64()?, decoder.u32()?);
+        let new = (decoder.u64()?, decoder.u32()?);
+        let version = decoder.u32()?;
+        let max_size = decoder.u64()?;
+        let seed = decoder.u32()?;
+        let options = decoder.u32()?;
+        let alignment = decoder.u32()?;
+        let features = Features::from_bits(decoder.u32()?);
+        let heap_threshold = decoder.u64()?.checked_sub(1).map(to_usize).transpose()?;
+        let filter_rate = f64::from_bits(decoder.u64()?);
+        let checksum = decoder.u32()?;
+        let table_size = run_count.checked_mul(RUN_SIZE).ok_or(Error::Overflow(run_count as u64))?;
+        let table = decoder.take(table_size)?;
+        if hash::checksum(&[&buf[..HEADER_FIELDS_SIZE], table].concat()) != checksum {
+            return Err(Error::RunTableChecksum);
+        }
+        let mut decoder = Decoder::new(table);
+        let runs = (0..run_count).map(|_| DeltaRun::decode(&mut decoder)).collect::<Result<Vec<_>>>()?;
+        for run in runs.iter() {
+            let end = run.offset.checked_add(run.length).ok_or(Error::Overflow(run.offset as u64))?;
+            if end > buf.len() {
+                return Err(Error::Truncated {
+                    needed: end,
+                    available: buf.len(),
+                });
+            }
+        }
+        let settings = Settings {
+            version,
+            max_size,
+            seed,
+            options,
+            alignment,
+            features,
+            heap_threshold,
+            filter_rate,
+        };
+        Ok((Self { old, new, settings }, runs))
+    }
+}
+
+/// Changes to one shelf of the new file
+#[derive(Clone, Copy, Debug)]
+struct DeltaRun {
+    kind: RunKind,
+    codec: Option<Codec>,
+    block_size: usize,
+    // Entries per chunk of a compressed shelf
+    chunk_entries: usize,
+    removed: usize,
+    added: usize,
+    // Location and checksum of the change records in the delta file
+    offset: usize,
+    length: usize,
+    checksum: u32,
+}
+
+impl DeltaRun {
+    fn write_out<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_all(&(self.kind as u32).to_le_bytes())?;
+        writer.write_all(&self.codec.map_or(0, |codec| codec as u32).to_le_bytes())?;
+        for field in [self.block_size, self.chunk_entries, self.removed, self.added, self.offset, self.length] {
+            writer.write_all(&(field as u64).to_le_bytes())?;
+        }
+        writer.write_all(&self.checksum.to_le_bytes())?;
+        Ok(())
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<Self> {
+        let kind = RunKind::try_from(decoder.u32()?)?;
+        let codec = match decoder.u32()? {
+            0 => None,
+            codec => Some(Codec::try_from(codec)?),
+        };
+        Ok(Self {
+            kind,
+            codec,
+            block_size: to_usize(decoder.u64()?)?,
+            chunk_entries: to_usize(decoder.u64()?)?,
+            removed: to_usize(decoder.u64()?)?,
+            added: to_usize(decoder.u64()?)?,
+            offset: to_usize(decoder.u64()?)?,
+            length: to_usize(decoder.u64()?)?,
+            checksum: decoder.u32()?,
+        })
+    }
+}
+
+/// Entries of `old` belonging on the shelf `key` of a file pressed with `settings`, in on-disk order
+fn old_entries<'a>(old: &'a Reader, settings: &Settings, key: (RunKind, usize)) -> Result<impl Iterator<Item = Result<Entry<'a>>>> {
+    let route = settings.route();
+    let mut sources: Vec<Box<dyn Iterator<Item = Result<Entry>> + 'a>> = Vec::new();
+    for shelf in old.shelves() {
+        match shelf.kind() {
+            RunKind::Fixed if route.shelf_for(shelf.block_size()) == key => sources.push(shelf_entries(shelf)),
+            RunKind::Heap if key.0 == RunKind::Heap || key.1 >= shelf.block_size() => {
+                let belongs = move |entry: &Result<Entry>| entry.as_ref().map_or(true, |entry| route.shelf_for(entry.data.len()) == key);
+                sources.push(Box::new(shelf_entries(shelf).filter(belongs)));
+            }
+            _ => {}
+        }
+    }
+    Ok(Merge::new(sources, false)?.map(|merged| merged.map(|merged| merged.entry)))
+}
+
+/// Position of `entry` in on-disk order, files without values sort as if every value was empty
+fn order<'e>(entry: &'e Entry) -> (u32, &'e [u8], &'e [u8]) {
+    (entry.hash, &entry.data, entry.value.as_deref().unwrap_or_default())
+}
+
+/// Which side of a diff an entry is only on
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Change {
+    Removed,
+    Added,
+}
+
+/// Walk two streams of entries in on-disk order, handing `change` the entries only one of them holds
+fn compare<'a, 'b>(
+    old: impl Iterator<Item = Result<Entry<'a>>>,
+    new: impl Iterator<Item = Result<Entry<'b>>>,
+    mut change: impl FnMut(Change, &Entry) -> Result<()>,
+) -> Result<()> {
+    let (mut old, mut new) = (old.peekable(), new.peekable());
+    loop {
+        let ordering = match (old.peek(), new.peek()) {
+            (Some(Ok(old)), Some(Ok(new))) => order(old).cmp(&order(new)),
+            (Some(_), None) | (Some(Err(_)), _) => Ordering::Less,
+            (None, Some(_)) | (_, Some(Err(_))) => Ordering::Greater,
+            (None, None) => return Ok(()),
+        };
+        match ordering {
+            Ordering::Less => change(Change::Removed, &old.next().transpose()?.ok_or("Entry vanished")?)?,
+            Ordering::Greater => change(Change::Added, &new.next().transpose()?.ok_or("Entry vanished")?)?,
+            Ordering::Equal => {
+                old.next();
+                new.next();
+            }
+        }
+    }
+}
+
+/// Stream the entries of `old` but `removed`, merged with `added`, to `push`, all in on-disk order
+fn patch<'a, 'b>(
+    old: impl Iterator<Item = Result<Entry<'a>>>,
+    removed: impl Iterator<Item = Result<Entry<'b>>>,
+    added: impl Iterator<Item = Result<Entry<'b>>>,
+    mut push: impl FnMut(&Entry) -> Result<()>,
+) -> Result<()> {
+    let (mut removed, mut added) = (removed.peekable(), added.peekable());
+    let missing = |entry: &Entry| Error::from(format!("The delta removes a block of {} bytes the old file doesn't hold", entry.data.len()));
+    for entry in old {
+        let entry = entry?;
+        while let Some(next) = added.next_if(|next| next.as_ref().map_or(true, |next| order(next) < order(&entry))) {
+            push(&next?)?;
+        }
+        match removed.next_if(|gone| gone.as_ref().map_or(true, |gone| order(gone) <= order(&entry))) {
+            Some(gone) => {
+                let gone = gone?;
+                if order(&gone) != order(&entry) {
+                    return Err(missing(&gone));
+                }
+            }
+            None => push(&entry)?,
+        }
+    }
+    if let Some(gone) = removed.next() {
+        return Err(missing(&gone?));
+    }
+    for next in added {
+        push(&next?)?;
+    }
+    Ok(())
+}
+
+/// Split the change records of a run into the removed and the added ones
+fn split_changes(changes: &[u8], removed: usize) -> Result<(&[u8], &[u8])> {
+    let mut decoder = Decoder::new(changes);
+    for _ in 0..removed {
+        let length = to_usize(decoder.u64()?)?;
+        let value_length = to_usize(decoder.u64()?)?.saturating_sub(1);
+        decoder.take(length.checked_add(value_length).ok_or(Error::Overflow(length as u64))?)?;
+    }
+    Ok(changes.split_at(decoder.consumed))
+}
+
+/// Write the change record of `entry`, returning its size
+fn write_record<W: Write>(writer: &mut W, entry: &Entry) -> Result<usize> {
+    writer.write_all(&(entry.data.len() as u64).to_le_bytes())?;
+    let value_length = entry.value.as_ref().map_or(0, |value| value.len() as u64 + 1);
+    writer.write_all(&value_length.to_le_bytes())?;
+    writer.write_all(&entry.data)?;
+    let value = entry.value.as_deref().unwrap_or_default();
+    writer.write_all(value)?;
+    Ok(RECORD_FIELDS_SIZE + entry.data.len() + value.len())
+}
+
+/// Reads change records back as entries
+struct Records<'a> {
+    decoder: Decoder<'a>,
+    seed: u32,
+}
+
+impl<'a> Records<'a> {
+    fn new(changes: &'a [u8], seed: u32) -> Self {
+        Self {
+            decoder: Decoder::new(changes),
+            seed,
+        }
+    }
+
+    fn record(&mut self) -> Result<Option<Entry<'a>>> {
+        if self.decoder.is_empty() {
+            return Ok(None);
+        }
+        let length = to_usize(self.decoder.u64()?)?;
+        let value_length = to_usize(self.decoder.u64()?)?;
+        let data = self.decoder.take(length)?;
+        let value = value_length.checked_sub(1).map(|length| self.decoder.take(length)).transpose()?;
+        Ok(Some(Entry {
+            hash: hash::hash(self.seed, data),
+            data: data.into(),
+            value: value.map(Into::into),
+        }))
+    }
+}
+
+impl<'a> Iterator for Records<'a> {
+    type Item = Result<Entry<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.record().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CollectorBuilder;
+    use tempfile::tempfile;
+
+    fn press(builder: CollectorBuilder, keys: impl Iterator<Item = usize>, tag: &str) -> (Vec<u8>, Reader) {
+        let mut collector = builder.build().unwrap();
+        for n in keys {
+            let key = "d".repeat(n % 25) + &n.to_string();
+            // Without a tag the blocks go in without values
+            match tag {
+                "" => collector.add(key),
+                tag => collector.insert(key, format!("{tag}{}", n % 7)),
+            }
+            .unwrap();
+        }
+        let mut pressed = Vec::new();
+        collector.press(&mut pressed).unwrap();
+        let mut file = tempfile().unwrap();
+        file.write_all(&pressed).unwrap();
+        (pressed, Reader::from_file(&file).unwrap())
+    }
+
+    fn round_trip(old: &Reader, new: &Reader, expected: &[u8]) -> (DiffStats, Delta) {
+        let mut file = tempfile().unwrap();
+        let stats = diff(old, new, &mut file).unwrap();
+        let delta = Delta::from_file(&file).unwrap();
+        assert_eq!((delta.removed(), delta.added()), (stats.removed, stats.added));
+        let mut applied = Vec::new();
+        let written = apply(old, &delta, &mut applied).unwrap();
+        assert_eq!(written, applied.len());
+        assert!(applied == expected);
+        (stats, delta)
+    }
+
+    #[test]
+    fn apply_reproduces_new_file() {
+        let builder = Collector::builder().heap_threshold(20).perfect_hash(true).filter(0.01);
+        let (_, old) = press(builder.clone(), 0..1000, "a");
+        // Shift the range by a hundred and drop every 25th block
+        let (pressed, new) = press(builder.clone(), (100..1100).filter(|n| n % 25 != 5), "a");
+        let (stats, _) = round_trip(&old, &new, &pressed);
+        assert_eq!(stats.removed, 100 + 36);
+        assert_eq!(stats.added, 100 - 4);
+        assert!(stats.written < pressed.len() / 2);
+
+        let (pressed, same) = press(builder.clone(), 0..1000, "a");
+        let (stats, _) = round_trip(&old, &same, &pressed);
+        assert_eq!((stats.removed, stats.added), (0, 0));
+        // Changed values are removed and added again
+        let (pressed, revalued) = press(builder, 0..1000, "b");
+        let (stats, _) = round_trip(&old, &revalued, &pressed);
+        assert_eq!((stats.removed, stats.added), (1000, 1000));
+    }
+
+    #[test]
+    fn apply_across_settings() {
+        let mut builders = vec![
+            Collector::builder(),
+            Collector::builder().heap_threshold(10).alignment(16),
+            Collector::builder().heap_threshold(30).dedup(false),
+            Collector::builder().version(2).heap_threshold(25),
+        ];
+        builders.extend(
+            [Codec::Lz4, Codec::Zstd]
+                .into_iter()
+                .filter(Codec::is_available)
+                .map(|codec| Collector::builder().heap_threshold(15).compression(codec).chunk_size(300)),
+        );
+        for old_builder in builders.iter() {
+            let (_, old) = press(old_builder.clone(), 0..500, "");
+            for new_builder in builders.iter() {
+                let (pressed, new) = press(new_builder.clone(), 250..800, "");
+                round_trip(&old, &new, &pressed);
+            }
+        }
+    }
+
+    #[test]
+    fn apply_checks_inputs() {
+        let (_, old) = press(Collector::builder(), 0..300, "a");
+        let (_, new) = press(Collector::builder(), 100..400, "a");
+        let mut delta = Vec::new();
+        diff(&old, &new, &mut delta).unwrap();
+        let mut file = tempfile().unwrap();
+        file.write_all(&delta).unwrap();
+        let parsed = Delta::from_file(&file).unwrap();
+        assert!(apply(&new, &parsed, &mut Vec::new()).is_err());
+
+        // A flipped byte in the changes or the run table is caught
+        for position in [delta.len() - 1, HEADER_SIZE + 1] {
+            let mut corrupt = delta.clone();
+            corrupt[position] ^= 1;
+            let mut file = tempfile().unwrap();
+            file.write_all(&corrupt).unwrap();
+            let result = Delta::from_file(&file).and_then(|delta| apply(&old, &delta, &mut Vec::new()));
+            assert!(result.is_err());
+        }
+        let (_, other_seed) = press(Collector::builder().seed(7), 0..300, "a");
+        assert!(diff(&old, &other_seed, &mut Vec::new()).is_err());
+    }
+}
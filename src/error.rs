@@ -2,6 +2,8 @@
 use thiserror::Error;
 use std::io;
 
+use crate::RunKind;
+
 pub type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Error, Debug)]
@@ -10,12 +12,32 @@ pub enum Error {
     BadMagic,
     #[error("Invalid header version")]
     InvalidVersion,
-    #[error("IoError")]
+    #[error("IoError: {0}")]
     IoError(#[from] io::Error),
-    #[error("Internal")]
+    #[error("{0}")]
     Internal(String),
-    #[error("Block Too Large")]
+    #[error("Block Too Large ({0} bytes)")]
     TooLarge(usize),
+    #[error("Input truncated, needed {needed} bytes but only {available} available")]
+    Truncated { needed: usize, available: usize },
+    #[error("Value {0} out of range for the file format or platform")]
+    Overflow(u64),
+    #[error("File requires unsupported features {0:#x}")]
+    UnsupportedFeatures(u32),
+    #[error("Header checksum mismatch")]
+    HeaderChecksum,
+    #[error("Footer checksum mismatch")]
+    FooterChecksum,
+    #[error("Run table checksum mismatch")]
+    RunTableChecksum,
+    #[error("Bulk region checksum mismatch in run {run}")]
+    BulkChecksum { run: usize, block_size: usize },
+    #[error("Extension region checksum mismatch in run {run}")]
+    ExtensionChecksum { run: usize, block_size: usize },
+    #[error("Block hash mismatch")]
+    HashMismatch { block_size: usize, index: usize },
+    #[error("Block {index} of the {kind:?} shelf for {block_size} bytes lies outside its run")]
+    OutsideRun { kind: RunKind, block_size: usize, index: usize },
 }
 
 // Can't use AsRef<str> here because io::Error does too
@@ -0,0 +1,154 @@
+//! Per-run extension regions
+//!
+//! From format version 3 on every run may carry an extension region right
+//! after its bulk region. It holds a sequence of records, each a `u32` tag
+//! and a `u64` payload length, both little-endian, followed by the payload.
+//! Records let features attach data to a run without changing the layout of
+//! the bulk region, readers skip the tags they don't know.
+
+use std::{io::Write, mem::size_of};
+
+use crate::{Decoder, Error, Result};
+
+/// Encoded size of a record's tag and length
+pub const RECORD_HEADER_SIZE: usize = 12;
+
+/// Size of an entry in a value table's offset array
+const VALUE_OFFSET_SIZE: usize = size_of::<u64>();
+
+/// Iterator over the records of an extension region, yielding `(tag, payload)`
+pub struct Extensions<'a> {
+    decoder: Decoder<'a>,
+    failed: bool,
+}
+
+impl<'a> Extensions<'a> {
+    /// Tag of the value table, see [`crate::Collector::insert`]
+    pub const VALUES: u32 = 1;
+    /// Tag of the minimal perfect hash index, see [`crate::CollectorBuilder::perfect_hash`]
+    pub const PERFECT_HASH: u32 = 2;
+    /// Tag of the approximate membership filter, see [`crate::CollectorBuilder::filter`]
+    pub const FILTER: u32 = 3;
+    /// Tag of the chunk index of a compressed run, see [`crate::CollectorBuilder::compression`]
+    pub const CHUNKS: u32 = 4;
+
+    pub fn new(region: &'a [u8]) -> Self {
+        Self {
+            decoder: Decoder::new(region),
+            failed: false,
+        }
+    }
+
+    fn record(&mut self) -> Result<(u32, &'a [u8])> {
+        let tag = self.decoder.u32()?;
+        let length = self.decoder.u64()?;
+        let length = usize::try_from(length).map_err(|_| Error::Overflow(length))?;
+        Ok((tag, self.decoder.take(length)?))
+    }
+}
+
+impl<'a> Iterator for Extensions<'a> {
+    type Item = Result<(u32, &'a [u8])>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed || self.decoder.is_empty() {
+            return None;
+        }
+        let result = self.record();
+        // A bad length leaves the following records unreachable
+        self.failed = result.is_err();
+        Some(result)
+    }
+}
+
+/// Append a record to an extension region, returning the number of bytes written
+pub(crate) fn write_record<W: Write>(writer: &mut W, tag: u32, payload: &[u8]) -> Result<usize> {
+    write_record_header(writer, tag, payload.len() as u64)?;
+    writer.write_all(payload)?;
+    Ok(RECORD_HEADER_SIZE + payload.len())
+}
+
+/// Start a record whose `length` bytes of payload the caller writes next
+pub(crate) fn write_record_header<W: Write>(writer: &mut W, tag: u32, length: u64) -> Result<()> {
+    writer.write_all(&tag.to_le_bytes())?;
+    writer.write_all(&length.to_le_bytes())?;
+    Ok(())
+}
+
+/// Values of a key/value run, in the same order as the run's block entries
+///
+/// Encoded as `count + 1` little-endian `u64` offsets into the value data
+/// that follows them, value `i` spans from offset `i` to offset `i + 1`.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct ValueTable<'a> {
+    offsets: &'a [u8],
+    data: &'a [u8],
+}
+
+impl<'a> ValueTable<'a> {
+    /// Value table of a run holding `count` blocks, None if the payload is too short
+    pub fn new(payload: &'a [u8], count: usize) -> Option<Self> {
+        let size = count.checked_add(1)?.checked_mul(VALUE_OFFSET_SIZE)?;
+        if payload.len() < size {
+            return None;
+        }
+        let (offsets, data) = payload.split_at(size);
+        Some(Self { offsets, data })
+    }
+
+    pub fn get(&self, index: usize) -> Option<&'a [u8]> {
+        let offset = |index: usize| {
+            let start = index.checked_mul(VALUE_OFFSET_SIZE)?;
+            let bytes = self.offsets.get(start..start + VALUE_OFFSET_SIZE)?;
+            usize::try_from(Decoder::new(bytes).u64().ok()?).ok()
+        };
+        self.data.get(offset(index)?..offset(index.checked_add(1)?)?)
+    }
+
+    /// Encode `values` as a value table payload
+    pub fn encode<'b>(values: impl ExactSizeIterator<Item = &'b [u8]> + Clone) -> Vec<u8> {
+        let data_size: usize = values.clone().map(<[u8]>::len).sum();
+        let mut payload = Vec::with_capacity((values.len() + 1) * VALUE_OFFSET_SIZE + data_size);
+        let mut offset = 0u64;
+        payload.extend_from_slice(&offset.to_le_bytes());
+        for value in values.clone() {
+            offset += value.len() as u64;
+            payload.extend_from_slice(&offset.to_le_bytes());
+        }
+        for value in values {
+            payload.extend_from_slice(value);
+        }
+        payload
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_round_trip() {
+        let mut region = Vec::new();
+        write_record(&mut region, 7, b"payload").unwrap();
+        write_record(&mut region, 9, b"").unwrap();
+        let records = Extensions::new(&region).collect::<Result<Vec<_>>>().unwrap();
+        assert_eq!(records, vec![(7, &b"payload"[..]), (9, &b""[..])]);
+
+        let mut records = Extensions::new(&region[..region.len() - 1]);
+        assert!(records.next().unwrap().is_ok());
+        assert!(matches!(records.next(), Some(Err(Error::Truncated { .. }))));
+        assert!(records.next().is_none());
+    }
+
+    #[test]
+    fn value_table() {
+        let values = [&b"one"[..], b"", b"three"];
+        let payload = ValueTable::encode(values.iter().copied());
+        let table = ValueTable::new(&payload, values.len()).unwrap();
+        for (index, value) in values.iter().enumerate() {
+            assert_eq!(table.get(index), Some(*value));
+        }
+        assert_eq!(table.get(3), None);
+        assert!(ValueTable::new(&payload[..31], values.len()).is_none());
+    }
+}
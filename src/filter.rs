@@ -0,0 +1,264 @@
+//! Approximate membership filter checked before a shelf's bulk region
+//!
+//! A blocked Bloom filter: every key sets its bits within a single 64-byte
+//! block, so a negative lookup costs one cache miss. Keys are 64 bits, the
+//! stored block hash in the high half and a second hash of the block under
+//! the filter's own seed in the low half, which keeps the false positive rate
+//! from bottoming out at the collision rate of the 32-bit block hashes.
+//!
+//! Sizing the filter only uses basic floating point arithmetic, which IEEE 754
+//! rounds the same way everywhere, so a shelf gets the same filter on every
+//! platform. The standard library's `exp`, `ln` and `powf` call into the
+//! platform's math library, whose results may differ in the last bit.
+//!
+//! The encoding is the block count as a little-endian `u64`, the configured
+//! false positive rate as the bits of an `f64`, the number of bits set per key
+//! and the filter seed as `u32`s, then the filter blocks.
+
+use std::mem::size_of;
+
+use crate::{hash, Decoder, Error, Result};
+
+/// Bytes per filter block, one cache line
+const BLOCK_SIZE: usize = 64;
+const BLOCK_BITS: u64 = BLOCK_SIZE as u64 * 8;
+const FIELDS_SIZE: usize = 2 * size_of::<u64>() + 2 * size_of::<u32>();
+/// Most bits set per key
+const MAX_HASHES: u32 = 16;
+
+/// Derive the filter seed from the block hash seed
+pub(crate) fn seed(block_seed: u32) -> u32 {
+    block_seed ^ 0x9E37_79B9
+}
+
+/// Filter key of a block whose stored hash is `block_hash`
+pub(crate) fn key(block_hash: u32, filter_seed: u32, data: &[u8]) -> u64 {
+    u64::from(block_hash) << 32 | u64::from(hash::hash(filter_seed, data))
+}
+
+/// Probe positions of `key`, the block index and the bit indexes inside it
+fn probes(key: u64, blocks: usize, hashes: u32) -> (usize, impl Iterator<Item = u64>) {
+    // The high half of the spread key picks the block, a generator seeded with
+    // it picks the bits. Double hashing correlates too much within 512 bits.
+    let mixed = hash::mix(key);
+    let block = (((mixed >> 32) * blocks as u64) >> 32) as usize;
+    let mut state = hash::mix(mixed);
+    let bits = (0..hashes).map(move |_| {
+        state = state.wrapping_mul(0x5851_F42D_4C95_7F2D).wrapping_add(0x1405_7B7E_F767_814F);
+        hash::mix(state) % BLOCK_BITS
+    });
+    (block, bits)
+}
+
+/// `e^-x` for `x >= 0`, halving `x` until a short Taylor series is exact and squaring back
+fn exp_neg(x: f64) -> f64 {
+    let mut halvings = 0;
+    let mut reduced = x;
+    while reduced > 0.5 {
+        reduced /= 2.0;
+        halvings += 1;
+    }
+    let (mut sum, mut term) = (1.0, 1.0);
+    for n in 1..20 {
+        term *= -reduced / f64::from(n);
+        sum += term;
+    }
+    for _ in 0..halvings {
+        sum *= sum;
+    }
+    sum
+}
+
+/// Natural logarithm of `x > 0`, from its binary exponent and an atanh series for the mantissa
+fn ln(x: f64) -> f64 {
+    let (mut mantissa, mut exponent) = (x, 0);
+    while mantissa >= 2.0 {
+        mantissa /= 2.0;
+        exponent += 1;
+    }
+    while mantissa < 1.0 {
+        mantissa *= 2.0;
+        exponent -= 1;
+    }
+    // ln(m) = 2 atanh((m - 1) / (m + 1)), the ratio stays below 1/3
+    let ratio = (mantissa - 1.0) / (mantissa + 1.0);
+    let square = ratio * ratio;
+    let (mut sum, mut power) = (0.0, ratio);
+    for n in 0..30 {
+        sum += power / f64::from(2 * n + 1);
+        power *= square;
+    }
+    f64::from(exponent) * std::f64::consts::LN_2 + 2.0 * sum
+}
+
+/// `base` raised to `exponent` by squaring
+fn pow(mut base: f64, mut exponent: u32) -> f64 {
+    let mut result = 1.0;
+    while exponent > 0 {
+        if exponent & 1 == 1 {
+            result *= base;
+        }
+        base *= base;
+        exponent >>= 1;
+    }
+    result
+}
+
+/// Expected false positive rate of a blocked filter
+///
+/// Keys per block follow a Poisson distribution, blocks that got more keys
+/// than the average are denser and let more misses through, which is what
+/// makes blocking cost extra bits over a plain Bloom filter.
+fn expected_rate(bits_per_key: f64, hashes: u32) -> f64 {
+    let mean = BLOCK_BITS as f64 / bits_per_key;
+    // Chance that one key leaves a given bit of its block clear
+    let clear_per_key = pow(1.0 - 1.0 / BLOCK_BITS as f64, hashes);
+    let (mut rate, mut probability, mut clear) = (0.0, exp_neg(mean), 1.0);
+    for keys in 0..(mean * 4.0 + 64.0) as u32 {
+        rate += probability * pow(1.0 - clear, hashes);
+        probability *= mean / f64::from(keys + 1);
+        clear *= clear_per_key;
+    }
+    rate
+}
+
+/// Smallest bits per key and the number of bits set per key reaching `rate`
+fn dimensions(rate: f64) -> (f64, u32) {
+    // Start from what a plain Bloom filter needs
+    let mut bits_per_key = (-ln(rate) / (std::f64::consts::LN_2 * std::f64::consts::LN_2)).max(1.0);
+    loop {
+        let best = (1..=MAX_HASHES)
+            .map(|hashes| (expected_rate(bits_per_key, hashes), hashes))
+            .min_by(|a, b| a.0.total_cmp(&b.0))
+            .map_or(1, |(_, hashes)| hashes);
+        if expected_rate(bits_per_key, best) <= rate || bits_per_key >= BLOCK_BITS as f64 {
+            return (bits_per_key, best);
+        }
+        bits_per_key += 0.25;
+    }
+}
+
+/// Build a filter over `keys` with false positive rate `rate`
+pub(crate) fn build(keys: &[u64], rate: f64, filter_seed: u32) -> Vec<u8> {
+    let (bits_per_key, hashes) = dimensions(rate);
+    let blocks = ((keys.len() as f64 * bits_per_key / BLOCK_BITS as f64).ceil() as usize).max(1);
+
+    let mut payload = Vec::with_capacity(FIELDS_SIZE + blocks * BLOCK_SIZE);
+    payload.extend_from_slice(&(blocks as u64).to_le_bytes());
+    payload.extend_from_slice(&rate.to_bits().to_le_bytes());
+    payload.extend_from_slice(&hashes.to_le_bytes());
+    payload.extend_from_slice(&filter_seed.to_le_bytes());
+    payload.resize(FIELDS_SIZE + blocks * BLOCK_SIZE, 0);
+    let filter = &mut payload[FIELDS_SIZE..];
+    for key in keys {
+        let (block, bits) = probes(*key, blocks, hashes);
+        let block = &mut filter[block * BLOCK_SIZE..][..BLOCK_SIZE];
+        for bit in bits {
+            block[(bit / 8) as usize] |= 1 << (bit % 8);
+        }
+    }
+    payload
+}
+
+/// Borrowed view of an encoded filter
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Filter<'a> {
+    rate: f64,
+    hashes: u32,
+    seed: u32,
+    blocks: &'a [u8],
+}
+
+impl<'a> Filter<'a> {
+    pub fn new(payload: &'a [u8]) -> Result<Self> {
+        let mut decoder = Decoder::new(payload);
+        let blocks = decoder.u64()?;
+        let rate = f64::from_bits(decoder.u64()?);
+        let hashes = decoder.u32()?;
+        let seed = decoder.u32()?;
+        if !(rate > 0.0 && rate < 1.0) {
+            return Err(format!("Invalid filter false positive rate {rate}").into());
+        }
+        if hashes == 0 || hashes > MAX_HASHES {
+            return Err(format!("Invalid filter hash count {hashes}").into());
+        }
+        let size = usize::try_from(blocks)
+            .ok()
+            .and_then(|blocks| blocks.checked_mul(BLOCK_SIZE))
+            .filter(|size| *size > 0)
+            .ok_or(Error::Overflow(blocks))?;
+        Ok(Self {
+            rate,
+            hashes,
+            seed,
+            blocks: decoder.take(size)?,
+        })
+    }
+
+    /// False positive rate the filter was built for
+    pub fn rate(&self) -> f64 {
+        self.rate
+    }
+
+    pub fn seed(&self) -> u32 {
+        self.seed
+    }
+
+    /// False means the key is certainly missing
+    pub fn may_contain(&self, key: u64) -> bool {
+        let (block, mut bits) = probes(key, self.blocks.len() / BLOCK_SIZE, self.hashes);
+        let block = &self.blocks[block * BLOCK_SIZE..][..BLOCK_SIZE];
+        bits.all(|bit| block[(bit / 8) as usize] & (1 << (bit % 8)) != 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn portable_math() {
+        for x in [0.0f64, 0.1, 1.0, 7.5, 100.0, 512.0] {
+            let expected = (-x).exp();
+            assert!((exp_neg(x) - expected).abs() <= expected * 1e-12, "exp(-{x})");
+        }
+        for x in [1e-9f64, 0.0001, 0.3, 1.0, 2.0, 1e6] {
+            assert!((ln(x) - x.ln()).abs() <= 1e-12, "ln({x})");
+        }
+        assert_eq!(pow(3.0, 5), 243.0);
+        assert_eq!(pow(0.5, 0), 1.0);
+    }
+
+    #[test]
+    fn false_positive_rate() {
+        let keys: Vec<u64> = (0..20_000u32).map(|n| key(n.wrapping_mul(2_654_435_761), 1, &n.to_le_bytes())).collect();
+        for rate in [0.1, 0.01, 0.001, 0.0001] {
+            let payload = build(&keys, rate, 1);
+            let filter = Filter::new(&payload).unwrap();
+            assert_eq!(filter.rate(), rate);
+            assert!(keys.iter().all(|key| filter.may_contain(*key)));
+            let trials = 200_000u32;
+            let positives = (0..trials)
+                .map(|n| key(n.wrapping_mul(40_503), 1, &(n + 1_000_000).to_le_bytes()))
+                .filter(|key| filter.may_contain(*key))
+                .count();
+            let measured = positives as f64 / f64::from(trials);
+            assert!(measured < rate * 1.5, "rate {rate} measured {measured}");
+        }
+        let payload = build(&[], 0.01, 1);
+        assert!(!Filter::new(&payload).unwrap().may_contain(0));
+        assert!(Filter::new(&payload[..payload.len() - 1]).is_err());
+
+        // Corrupt parameters are rejected rather than probed
+        let corrupt = |at: usize, field: &[u8]| {
+            let mut payload = payload.clone();
+            payload[at..at + field.len()].copy_from_slice(field);
+            Filter::new(&payload).is_err()
+        };
+        assert!(corrupt(16, &0u32.to_le_bytes()));
+        assert!(corrupt(16, &u32::MAX.to_le_bytes()));
+        for rate in [0.0, 1.0, -0.5, f64::NAN, f64::INFINITY] {
+            assert!(corrupt(8, &rate.to_bits().to_le_bytes()), "{rate}");
+        }
+    }
+}
@@ -0,0 +1,103 @@
+//! Block hashing
+//!
+//! Blocks are hashed with 32-bit xxHash (XXH32). Input is consumed as
+//! little-endian words, so the same bytes and seed produce the same hash on
+//! every platform and the stored hashes are part of the file format.
+
+/// Seed used when a collector is not given one explicitly
+pub const DEFAULT_SEED: u32 = 0x5EED_0DD1;
+
+const PRIME_1: u32 = 0x9E37_79B1;
+const PRIME_2: u32 = 0x85EB_CA77;
+const PRIME_3: u32 = 0xC2B2_AE3D;
+const PRIME_4: u32 = 0x27D4_EB2F;
+const PRIME_5: u32 = 0x1656_67B1;
+
+/// XXH32 of `data` with the given seed
+pub fn hash(seed: u32, data: &[u8]) -> u32 {
+    let mut stripes = data.chunks_exact(16);
+    let mut acc = if data.len() >= 16 {
+        let mut lanes = [
+            seed.wrapping_add(PRIME_1).wrapping_add(PRIME_2),
+            seed.wrapping_add(PRIME_2),
+            seed,
+            seed.wrapping_sub(PRIME_1),
+        ];
+        for stripe in stripes.by_ref() {
+            for (lane, word) in lanes.iter_mut().zip(stripe.chunks_exact(4)) {
+                *lane = round(*lane, read_u32(word));
+            }
+        }
+        lanes[0]
+            .rotate_left(1)
+            .wrapping_add(lanes[1].rotate_left(7))
+            .wrapping_add(lanes[2].rotate_left(12))
+            .wrapping_add(lanes[3].rotate_left(18))
+    } else {
+        seed.wrapping_add(PRIME_5)
+    };
+    // The input length is mixed in modulo 2^32, as the reference does
+    acc = acc.wrapping_add(data.len() as u32);
+
+    let mut words = stripes.remainder().chunks_exact(4);
+    for word in words.by_ref() {
+        acc = acc
+            .wrapping_add(read_u32(word).wrapping_mul(PRIME_3))
+            .rotate_left(17)
+            .wrapping_mul(PRIME_4);
+    }
+    for byte in words.remainder() {
+        acc = acc
+            .wrapping_add((*byte as u32).wrapping_mul(PRIME_5))
+            .rotate_left(11)
+            .wrapping_mul(PRIME_1);
+    }
+
+    acc ^= acc >> 15;
+    acc = acc.wrapping_mul(PRIME_2);
+    acc ^= acc >> 13;
+    acc = acc.wrapping_mul(PRIME_3);
+    acc ^= acc >> 16;
+    acc
+}
+
+/// Checksum protecting a region of a pressed file, XXH32 with a zero seed
+pub fn checksum(data: &[u8]) -> u32 {
+    hash(0, data)
+}
+
+/// Finalizer of SplitMix64, a bijection that spreads every input bit
+pub(crate) fn mix(mut value: u64) -> u64 {
+    value = (value ^ (value >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    value = (value ^ (value >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    value ^ (value >> 31)
+}
+
+fn round(acc: u32, input: u32) -> u32 {
+    acc.wrapping_add(input.wrapping_mul(PRIME_2))
+        .rotate_left(13)
+        .wrapping_mul(PRIME_1)
+}
+
+fn read_u32(word: &[u8]) -> u32 {
+    u32::from_le_bytes([word[0], word[1], word[2], word[3]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reference_vectors() {
+        assert_eq!(hash(0, b""), 0x02CC_5D05);
+        assert_eq!(hash(0, b"a"), 0x550D_7456);
+        assert_eq!(hash(0, b"abc"), 0x32D1_53FF);
+        assert_eq!(hash(0, b"Nobody inspects the spammish repetition"), 0xE229_3B2F);
+    }
+
+    #[test]
+    fn seed_changes_hash() {
+        let data = b"0123456789abcdef0123";
+        assert_ne!(hash(0, data), hash(DEFAULT_SEED, data));
+    }
+}
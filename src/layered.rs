@@ -0,0 +1,177 @@
+//! Reading a pressed file through layers of later changes
+//!
+//! Rewriting a large file for every small change is wasteful, so changes can
+//! be pressed into small files of their own and stacked on the original. A
+//! [`LayeredReader`] answers lookups by asking the layers from the top down,
+//! and merges them like [`crate::union`] does when iterating or compacting.
+
+use std::io::Write;
+
+use crate::{
+    merge::{self, MergeStats, SetBlocks},
+    Features, Reader, Result,
+};
+
+/// What the blocks of a layer do to the layers under it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Layer {
+    /// The blocks are added, with their values if any
+    Additions,
+    /// The blocks are deleted, their values are ignored
+    Tombstones,
+}
+
+/// A base file under layers of additions and tombstones, read as if they had been merged
+///
+/// A block is held if the topmost file holding it is the base or an additions
+/// layer. Its value is the one from the topmost file above every tombstone for
+/// it that has values, like re-inserting the blocks of each layer in turn
+/// into a [`crate::Collector`] would give.
+#[derive(Debug)]
+pub struct LayeredReader {
+    // The base, then the layers bottom up
+    readers: Vec<Reader>,
+    tombstones: Vec<bool>,
+}
+
+impl LayeredReader {
+    pub fn new(base: Reader) -> Self {
+        Self {
+            readers: vec![base],
+            tombstones: vec![false],
+        }
+    }
+
+    /// Stack `reader` on top of the layers so far, it must be hashed with the seed of the base
+    pub fn push(&mut self, layer: Layer, reader: Reader) -> Result<()> {
+        if reader.seed() != self.base().seed() {
+            return Err(format!("Layer hashed with seed {:#x} instead of the base's {:#x}", reader.seed(), self.base().seed()).into());
+        }
+        self.readers.push(reader);
+        self.tombstones.push(layer == Layer::Tombstones);
+        Ok(())
+    }
+
+    pub fn base(&self) -> &Reader {
+        &self.readers[0]
+    }
+
+    /// Number of layers on top of the base
+    pub fn layers(&self) -> usize {
+        self.readers.len() - 1
+    }
+
+    /// Files from the top down, with whether each holds tombstones
+    fn top_down(&self) -> impl Iterator<Item = (&Reader, bool)> {
+        self.readers.iter().zip(self.tombstones.iter().copied()).rev()
+    }
+
+    /// Whether `block` is held, see [`LayeredReader`]
+    pub fn contains<T: AsRef<[u8]>>(&self, block: T) -> bool {
+        let block = block.as_ref();
+        self.top_down()
+            .find(|(reader, _)| reader.contains(block))
+            .is_some_and(|(_, tombstone)| !tombstone)
+    }
+
+    /// Value of `key`, see [`Reader::get`] and [`LayeredReader`]
+    pub fn get<T: AsRef<[u8]>>(&self, key: T) -> Option<&[u8]> {
+        let key = key.as_ref();
+        let mut held = false;
+        for (reader, tombstone) in self.top_down() {
+            if !reader.contains(key) {
+                continue;
+            }
+            if tombstone {
+                break;
+            }
+            held = true;
+            if let Some(value) = reader.get(key) {
+                return Some(value);
+            }
+        }
+        // Merged, the key would get an empty value if any file with values took part
+        let values = self.top_down().any(|(reader, tombstone)| !tombstone && reader.header().optional_features().contains(Features::VALUES));
+        (held && values).then_some(&[])
+    }
+
+    /// Held blocks shelf by shelf, see [`SetBlocks`]
+    ///
+    /// The shelves and the settings of a file pressed from them are those of
+    /// [`crate::union`] on the base and the additions layers. Duplicates are
+    /// always dropped.
+    pub fn blocks(&self) -> Result<SetBlocks<'_>> {
+        merge::overlay(&self.readers, self.tombstones.clone())
+    }
+
+    /// Flatten the layers into a new file, returning what was written
+    ///
+    /// The file is the one a [`crate::Collector`] with the settings of
+    /// [`LayeredReader::blocks`] presses from the held blocks and their values.
+    pub fn compact<W: Write>(&self, writer: &mut W) -> Result<MergeStats> {
+        self.blocks()?.press(writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Collector, CollectorBuilder};
+    use tempfile::tempfile;
+
+    fn key(n: usize) -> String {
+        "l".repeat(n % 30) + &n.to_string()
+    }
+
+    fn press(builder: CollectorBuilder, keys: impl Iterator<Item = usize>, value: &str) -> Reader {
+        let mut collector = builder.build().unwrap();
+        for n in keys {
+            collector.insert(key(n), value).unwrap();
+        }
+        let mut file = tempfile().unwrap();
+        collector.press(&mut file).unwrap();
+        Reader::from_file(&file).unwrap()
+    }
+
+    #[test]
+    fn layers_read_as_merged() {
+        let base = Collector::builder().heap_threshold(20).perfect_hash(true);
+        let mut layered = LayeredReader::new(press(base.clone(), 0..1000, "base"));
+        layered.push(Layer::Additions, press(Collector::builder(), 1000..1200, "new")).unwrap();
+        // Tombstones with a lower heap threshold than the base
+        layered.push(Layer::Tombstones, press(Collector::builder().heap_threshold(5), 500..1100, "")).unwrap();
+        layered.push(Layer::Additions, press(Collector::builder().heap_threshold(20), 900..950, "back")).unwrap();
+        assert_eq!(layered.layers(), 3);
+        assert!(layered.push(Layer::Additions, press(Collector::builder().seed(3), 0..1, "")).is_err());
+
+        let held = |n: usize| !(500..1100).contains(&n) || (900..950).contains(&n);
+        let value = |n: usize| match n {
+            900..950 => "back",
+            1100.. => "new",
+            _ => "base",
+        };
+        for n in 0..1300 {
+            assert_eq!(layered.contains(key(n)), n < 1200 && held(n), "{n}");
+            let expected = (n < 1200 && held(n)).then(|| value(n).as_bytes());
+            assert_eq!(layered.get(key(n)), expected);
+        }
+
+        let mut blocks: Vec<Vec<u8>> = layered.blocks().unwrap().map(|block| block.unwrap().into_owned()).collect();
+        blocks.sort();
+        let mut expected: Vec<Vec<u8>> = (0..1200).filter(|n| held(*n)).map(|n| key(n).into_bytes()).collect();
+        expected.sort();
+        assert_eq!(blocks, expected);
+
+        // Compacting presses what a collector with the base's settings would
+        let mut compacted = Vec::new();
+        let stats = layered.compact(&mut compacted).unwrap();
+        assert_eq!(stats.blocks, expected.len());
+        let mut collector = base.build().unwrap();
+        for n in (0..1200).filter(|n| held(*n)) {
+            collector.insert(key(n), value(n)).unwrap();
+        }
+        let mut pressed = Vec::new();
+        collector.press(&mut pressed).unwrap();
+        assert!(compacted == pressed);
+    }
+}
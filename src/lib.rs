@@ -1,189 +1,1515 @@
 
 
 use memmap::{Mmap, MmapMut};
-use std::{collections::BTreeMap, fmt::Debug, io::Write, mem::size_of, slice::from_raw_parts};
+use std::{collections::{BTreeMap, HashMap}, fmt::Debug, fs::File, io::Write, mem::size_of, ops::Deref, path::PathBuf};
 
+pub use crate::append::AppendStats;
+pub use crate::compress::Codec;
+use crate::append::Footer;
+pub use crate::delta::{apply, diff, Delta, DiffStats};
 pub use crate::error::{Error, Result};
+pub use crate::extension::Extensions;
+pub use crate::merge::{difference, intersect, merge, union, MergeStats, SetBlocks};
+use crate::extension::ValueTable;
+pub use crate::layered::{Layer, LayeredReader};
+pub use crate::reader::{Blocks, Reader, ShelfRef};
+pub use crate::spill::SpillingCollector;
+pub use crate::upgrade::upgrade;
 
+mod append;
+mod compress;
+mod delta;
 mod error;
+mod extension;
+mod filter;
+pub mod hash;
+mod layered;
+mod merge;
+mod mph;
+mod parallel;
+mod reader;
+mod spill;
+mod spool;
+mod upgrade;
 
 pub fn store(map: &mut MmapMut, header: Header) -> Result<()> {
     let mut buf: &mut [u8] = map.as_mut();
-    buf.write(header.as_ref())?;
+    header.write_out(&mut buf)?;
     map.flush()?;
     Ok(())
 }
 
 
-#[repr(C, packed)]
+/// Reads little-endian fields off the front of a buffer, failing instead of reading past its end
+struct Decoder<'a> {
+    buf: &'a [u8],
+    consumed: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self { buf, consumed: 0 }
+    }
+
+    fn bytes<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let Some((field, rest)) = self.buf.split_first_chunk::<N>() else {
+            return Err(Error::Truncated {
+                needed: self.consumed + N,
+                available: self.consumed + self.buf.len(),
+            });
+        };
+        self.buf = rest;
+        self.consumed += N;
+        Ok(*field)
+    }
+
+    /// The next `length` bytes, unconverted
+    fn take(&mut self, length: usize) -> Result<&'a [u8]> {
+        if length > self.buf.len() {
+            return Err(Error::Truncated {
+                needed: self.consumed + length,
+                available: self.consumed + self.buf.len(),
+            });
+        }
+        let (field, rest) = self.buf.split_at(length);
+        self.buf = rest;
+        self.consumed += length;
+        Ok(field)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        self.bytes().map(u32::from_le_bytes)
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        self.bytes().map(u64::from_le_bytes)
+    }
+
+    /// Offset, count or size field, 32 bits wide in version 1 and 64 bits from version 2 on
+    fn word(&mut self, version: u32) -> Result<u64> {
+        if Header::wide(version) {
+            self.u64()
+        } else {
+            self.u32().map(u64::from)
+        }
+    }
+}
+
+/// Write an offset, count or size field in the width `version` uses
+fn write_word<W: Write>(writer: &mut W, value: u64, version: u32) -> Result<()> {
+    if Header::wide(version) {
+        writer.write_all(&value.to_le_bytes())?;
+    } else {
+        let value = u32::try_from(value).map_err(|_| Error::Overflow(value))?;
+        writer.write_all(&value.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+/// Size in bytes of an offset, count or size field in `version`
+const fn word_size(version: u32) -> usize {
+    if Header::wide(version) {
+        size_of::<u64>()
+    } else {
+        size_of::<u32>()
+    }
+}
+
+fn to_usize(value: u64) -> Result<usize> {
+    usize::try_from(value).map_err(|_| Error::Overflow(value))
+}
+
+/// Set of format features, one bit each
+///
+/// Files list the features they use twice in the [`Header`]: required
+/// features change how the file must be read and a reader that doesn't know
+/// one has to reject the file, optional features only add data a reader may
+/// ignore. Features are stored from version 3 on, older files have none.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Features(u32);
+
+impl Debug for Features {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Features({:#x})", self.0)
+    }
+}
+
+impl Features {
+    pub const NONE: Features = Features(0);
+    /// Optional, runs carry a value per block in a value table extension
+    pub const VALUES: Features = Features(1);
+    /// Optional, runs carry a minimal perfect hash index extension
+    pub const PERFECT_HASH: Features = Features(1 << 1);
+    /// Optional, runs carry an approximate membership filter extension
+    pub const FILTER: Features = Features(1 << 2);
+    /// Required, some shelves are LZ4 compressed
+    pub const LZ4: Features = Features(1 << 16);
+    /// Required, some shelves are Zstandard compressed
+    pub const ZSTD: Features = Features(1 << 17);
+    /// Required, the file ends in a footer locating the latest run table, see [`Collector::append`]
+    pub const APPENDABLE: Features = Features(1 << 18);
+    /// Required features this reader understands, the codecs depend on cargo features
+    pub const KNOWN_REQUIRED: Features = Features(
+        Features::APPENDABLE.0
+            | if cfg!(feature = "lz4") { Features::LZ4.0 } else { 0 }
+            | if cfg!(feature = "zstd") { Features::ZSTD.0 } else { 0 },
+    );
+
+    pub const fn bits(&self) -> u32 {
+        self.0
+    }
+
+    pub const fn from_bits(bits: u32) -> Self {
+        Features(bits)
+    }
+
+    pub const fn contains(&self, other: Features) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub const fn union(self, other: Features) -> Features {
+        Features(self.0 | other.0)
+    }
+
+    pub const fn difference(self, other: Features) -> Features {
+        Features(self.0 & !other.0)
+    }
+}
+
+#[derive(Clone, Copy)]
 pub struct Header {
     magic : u32,
     version : u32,
-    blocklist_size : u32,
+    blocklist_size : u64,
+    max_size : u64,
+    seed : u32,
+    options : u32,
+    alignment : u32,
+    required_features : Features,
+    optional_features : Features,
+    table_checksum : u32,
 }
 
 impl Debug for Header {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let magic = self.magic;
-        let version = self.version;
         f.debug_struct("Header")
-            .field("Magic", &format_args!("{:x}", magic))
-            .field("Version", &version)
+            .field("Magic", &format_args!("{:x}", self.magic))
+            .field("Version", &self.version)
+            .field("Shelves", &self.blocklist_size())
+            .field("MaxSize", &self.max_size())
+            .field("Seed", &format_args!("{:x}", self.seed()))
+            .field("Dedup", &self.dedup())
+            .field("Alignment", &self.alignment())
+            .field("RequiredFeatures", &self.required_features)
+            .field("OptionalFeatures", &self.optional_features)
+            .field("TableChecksum", &format_args!("{:x}", self.table_checksum))
             .finish()
     }
 }
 
-impl AsRef<[u8]> for Header {
-    fn as_ref(&self) -> &[u8] {
-        let ptr = self as *const Self as *const u8;
-        unsafe {
-            from_raw_parts(ptr, size_of::<Self>())
-        }
-    }
-}
-
 
 impl Header {
     const FILE_MAGIC: u32 = 0x55AA33BB;
+    /// Newest format version, the one pressed by default
+    ///
+    /// Version 1 uses 32-bit offsets and counts, version 2 widens them to 64
+    /// bits, version 3 adds feature flags and per-run extension regions.
+    pub const VERSION: u32 = 3;
+    /// Oldest format version still read and written
+    pub const MIN_VERSION: u32 = 1;
+    /// Version reported for files in the original version 1 layout, only read
+    ///
+    /// Those files were pressed before the header recorded any settings: a
+    /// 12-byte header of magic, version and shelf count, then 12-byte
+    /// descriptors of block size, count and offset for the fixed shelves,
+    /// with offsets counted from the end of the header, and no checksums.
+    /// The original writer stopped after the run table, so only files of an
+    /// empty collector open, the runs of any other file have no block data.
+    pub const LEGACY_VERSION: u32 = 0;
+    const OPTION_DEDUP: u32 = 1 << 0;
+
     fn new(blocklist_size: usize) -> Self {
-        let blocklist_size = blocklist_size as u32;
         Self {
             magic : Self::FILE_MAGIC,
-            version : 1,
-            blocklist_size,
+            version : Self::VERSION,
+            blocklist_size : blocklist_size as u64,
+            max_size : Collector::DEFAULT_MAX_SIZE as u64,
+            seed : hash::DEFAULT_SEED,
+            options : Self::OPTION_DEDUP,
+            alignment : 1,
+            required_features : Features::NONE,
+            optional_features : Features::NONE,
+            table_checksum : hash::checksum(&[]),
         }
     }
 
-    fn from_map<'a>(map: &'a Mmap) -> Result<&'a Header> {
+    /// Header of a file holding `runs` shelves pressed by `collector`
+    fn for_collector(collector: &Collector, runs: usize) -> Self {
+        let options = if collector.dedup { Self::OPTION_DEDUP } else { 0 };
+        let mut optional_features = Features::NONE;
+        if collector.values {
+            optional_features = optional_features.union(Features::VALUES);
+        }
+        if collector.perfect_hash {
+            optional_features = optional_features.union(Features::PERFECT_HASH);
+        }
+        if collector.filter.is_some() {
+            optional_features = optional_features.union(Features::FILTER);
+        }
+        let required_features = if collector.appendable { Features::APPENDABLE } else { Features::NONE };
+        Self {
+            version : collector.version,
+            max_size : collector.max_size as u64,
+            seed : collector.seed,
+            options,
+            alignment : collector.alignment as u32,
+            required_features,
+            optional_features,
+            ..Self::new(runs)
+        }
+    }
+
+    pub fn is_supported(version: u32) -> bool {
+        (Self::MIN_VERSION..=Self::VERSION).contains(&version)
+    }
+
+    /// Whether offsets, counts and sizes are 64 bits wide in `version`
+    const fn wide(version: u32) -> bool {
+        version >= 2
+    }
+
+    /// Whether `version` stores feature flags and run extensions
+    const fn has_features(version: u32) -> bool {
+        version >= 3
+    }
+
+    /// Encoded size in bytes of a header in `version`
+    pub const fn encoded_size(version: u32) -> usize {
+        if version == Self::LEGACY_VERSION {
+            return 3 * size_of::<u32>();
+        }
+        let features = if Self::has_features(version) { 2 * size_of::<u32>() } else { 0 };
+        7 * size_of::<u32>() + 2 * word_size(version) + features
+    }
+
+    /// Encoded size in bytes of this header
+    pub fn size(&self) -> usize {
+        Self::encoded_size(self.version)
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn blocklist_size(&self) -> usize {
+        // Checked to fit by Header::validate
+        self.blocklist_size as usize
+    }
+
+    /// Largest block the collector accepted when the file was pressed
+    pub fn max_size(&self) -> u64 {
+        self.max_size
+    }
+
+    /// Seed of the block hashes stored in the file
+    pub fn seed(&self) -> u32 {
+        self.seed
+    }
+
+    /// Whether duplicates were dropped when the file was collected
+    pub fn dedup(&self) -> bool {
+        self.options & Self::OPTION_DEDUP != 0
+    }
+
+    /// Alignment in bytes of every shelf's bulk region
+    pub fn alignment(&self) -> usize {
+        self.alignment as usize
+    }
+
+    /// Features a reader must understand to use the file
+    pub fn required_features(&self) -> Features {
+        self.required_features
+    }
+
+    /// Features a reader may ignore
+    pub fn optional_features(&self) -> Features {
+        self.optional_features
+    }
+
+    /// Checksum of the encoded run table
+    pub fn table_checksum(&self) -> u32 {
+        self.table_checksum
+    }
+
+    /// Byte range of the run table following the header
+    fn table_range(&self) -> Result<std::ops::Range<usize>> {
+        let end = RunDesc::encoded_size(self.version)
+            .checked_mul(self.blocklist_size())
+            .and_then(|table_size| table_size.checked_add(self.size()))
+            .ok_or(Error::Overflow(self.blocklist_size))?;
+        Ok(self.size()..end)
+    }
+
+    fn from_map(map: &Mmap) -> Result<Header> {
         Self::from_buf(map.as_ref())
     }
 
-    fn from_buf<'a>(buf: &'a [u8]) -> Result<&'a Header> {
-        let ptr = buf as *const [u8];
-        let ptr = ptr.cast::<Header>();
-        let header : Option<&'a Header> = unsafe { ptr.as_ref() };
-        let header = header.ok_or_else(|| Error::from("Pointer conversion failed"))?;
-        header.validate()
+    fn from_buf(buf: &[u8]) -> Result<Header> {
+        // A checksummed version 1 header that doesn't check out may be one in the original layout
+        match Self::decode(buf) {
+            Err(err @ (Error::Truncated { .. } | Error::HeaderChecksum)) => Self::decode_legacy(buf).map_err(|_| err),
+            result => result,
+        }
+    }
+
+    /// Decode a header in the original version 1 layout, see [`Header::LEGACY_VERSION`]
+    fn decode_legacy(buf: &[u8]) -> Result<Header> {
+        let mut decoder = Decoder::new(buf);
+        if decoder.u32()? != Self::FILE_MAGIC {
+            return Err(Error::BadMagic);
+        }
+        if decoder.u32()? != 1 {
+            return Err(Error::InvalidVersion);
+        }
+        let header = Self {
+            version : Self::LEGACY_VERSION,
+            blocklist_size : u64::from(decoder.u32()?),
+            options : 0,
+            ..Self::new(0)
+        };
+        // There is no checksum to check the run table against, take it as it is
+        let range = header.table_range()?;
+        let table = buf.get(range.clone()).ok_or(Error::Truncated {
+            needed: range.end,
+            available: buf.len(),
+        })?;
+        Ok(Self {
+            table_checksum : hash::checksum(table),
+            ..header
+        })
+    }
+
+    fn decode(buf: &[u8]) -> Result<Header> {
+        let mut decoder = Decoder::new(buf);
+        let magic = decoder.u32()?;
+        if magic != Self::FILE_MAGIC {
+            return Err(Error::BadMagic);
+        }
+        let version = decoder.u32()?;
+        if !Self::is_supported(version) {
+            return Err(Error::InvalidVersion);
+        }
+        let blocklist_size = decoder.word(version)?;
+        let max_size = decoder.word(version)?;
+        let seed = decoder.u32()?;
+        let options = decoder.u32()?;
+        let alignment = decoder.u32()?;
+        let (required_features, optional_features) = if Self::has_features(version) {
+            (Features(decoder.u32()?), Features(decoder.u32()?))
+        } else {
+            (Features::NONE, Features::NONE)
+        };
+        let header = Self {
+            magic,
+            version,
+            blocklist_size,
+            max_size,
+            seed,
+            options,
+            alignment,
+            required_features,
+            optional_features,
+            table_checksum : decoder.u32()?,
+        };
+        let checksum = decoder.u32()?;
+        header.validate(checksum)?;
+        Ok(header)
+    }
+
+    /// Checksum over the encoded fields, stored right behind them
+    fn checksum(&self) -> Result<u32> {
+        let mut buf = Vec::with_capacity(self.size());
+        self.write_fields(&mut buf)?;
+        Ok(hash::checksum(&buf))
     }
 
     fn write_out<W: Write>(&self, writer: &mut W) -> Result<usize> {
-        let mut size = writer.write(&self.magic.to_le_bytes())?;
-        size += writer.write(&self.version.to_le_bytes())?;
-        size += writer.write(&self.blocklist_size.to_le_bytes())?;
-        Ok(size)
+        let checksum = self.checksum()?;
+        self.write_fields(writer)?;
+        writer.write_all(&checksum.to_le_bytes())?;
+        Ok(self.size())
+    }
+
+    fn write_fields<W: Write>(&self, writer: &mut W) -> Result<()> {
+        if self.version == Self::LEGACY_VERSION {
+            return Err(Error::InvalidVersion);
+        }
+        writer.write_all(&self.magic.to_le_bytes())?;
+        writer.write_all(&self.version.to_le_bytes())?;
+        write_word(writer, self.blocklist_size, self.version)?;
+        write_word(writer, self.max_size, self.version)?;
+        writer.write_all(&self.seed.to_le_bytes())?;
+        writer.write_all(&self.options.to_le_bytes())?;
+        writer.write_all(&self.alignment.to_le_bytes())?;
+        if Self::has_features(self.version) {
+            writer.write_all(&self.required_features.bits().to_le_bytes())?;
+            writer.write_all(&self.optional_features.bits().to_le_bytes())?;
+        } else if !self.required_features.union(self.optional_features).is_empty() {
+            return Err(Error::InvalidVersion);
+        }
+        writer.write_all(&self.table_checksum.to_le_bytes())?;
+        Ok(())
     }
 
-    fn validate(&self) -> Result<&Self> {
+    fn validate(&self, checksum: u32) -> Result<&Self> {
         if self.magic != Self::FILE_MAGIC {
             return Err(Error::BadMagic);
         }
-        if self.version != 1 {
+        if !Self::is_supported(self.version) {
             return Err(Error::InvalidVersion);
         }
-        return Ok(self)
+        if checksum != self.checksum()? {
+            return Err(Error::HeaderChecksum);
+        }
+        let unknown = self.required_features.difference(Features::KNOWN_REQUIRED);
+        if !unknown.is_empty() {
+            return Err(Error::UnsupportedFeatures(unknown.bits()));
+        }
+        if !self.alignment().is_power_of_two() {
+            return Err(format!("Invalid alignment {}", self.alignment()).into());
+        }
+        to_usize(self.blocklist_size)?;
+        self.table_range()?;
+        Ok(self)
+    }
+}
+
+/// Layout of a run's bulk region
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(u32)]
+pub enum RunKind {
+    /// `count` blocks of exactly `block_size` bytes, each prefixed by its hash
+    Fixed = 0,
+    /// `count` index entries (hash, offset, length) followed by the packed block data,
+    /// `block_size` is the shortest length kept on the heap
+    Heap = 1,
+}
+
+impl TryFrom<u32> for RunKind {
+    type Error = Error;
+    fn try_from(value: u32) -> Result<RunKind> {
+        match value {
+            0 => Ok(RunKind::Fixed),
+            1 => Ok(RunKind::Heap),
+            other => Err(format!("Unknown run kind {other}").into()),
+        }
     }
 }
 
-#[repr(C, packed)]
+#[derive(Clone, Copy)]
 pub struct RunDesc {
-    block_size: u32,
-    count: u32,
-    offset: u32,
+    kind: u32,
+    block_size: u64,
+    count: u64,
+    offset: u64,
+    length: u64,
+    checksum: u32,
+    // Extension region right after the bulk region, version 3 and later
+    ext_length: u64,
+    ext_checksum: u32,
+}
+
+impl Debug for RunDesc {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RunDesc")
+            .field("kind", &RunKind::try_from(self.kind & Self::KIND_MASK).map_err(|_| self.kind))
+            .field("compressed", &self.is_compressed())
+            .field("block_size", &self.block_size)
+            .field("count", &self.count)
+            .field("offset", &self.offset)
+            .field("length", &self.length)
+            .field("checksum", &format_args!("{:x}", self.checksum))
+            .field("ext_length", &self.ext_length)
+            .field("ext_checksum", &format_args!("{:x}", self.ext_checksum))
+            .finish()
+    }
 }
 
 impl RunDesc {
-    fn from_map<'a>(map: &'a Mmap, offset: usize) -> Result<&'a RunDesc> {
-        Self::from_buf(map.as_ref())
+    /// Encoded size in bytes of a descriptor in `version`
+    pub const fn encoded_size(version: u32) -> usize {
+        if version == Header::LEGACY_VERSION {
+            3 * size_of::<u32>()
+        } else if Header::has_features(version) {
+            3 * size_of::<u32>() + 5 * word_size(version)
+        } else {
+            2 * size_of::<u32>() + 4 * word_size(version)
+        }
+    }
+
+    /// Low bits of the kind field hold the RunKind, the high bits flags
+    const KIND_MASK: u32 = 0xFFFF;
+    /// Flag for runs whose bulk region is a series of compressed chunks, version 3 and later
+    const COMPRESSED: u32 = 1 << 16;
+
+    pub fn kind(&self) -> RunKind {
+        // Checked by RunDesc::validate
+        RunKind::try_from(self.kind & Self::KIND_MASK).unwrap_or(RunKind::Fixed)
+    }
+
+    /// Whether the bulk region is compressed, the chunk index is in the extension region
+    pub fn is_compressed(&self) -> bool {
+        self.kind & Self::COMPRESSED != 0
+    }
+
+    // The usize accessors below are lossless once RunDesc::validate has
+    // checked the bulk region against an in-memory buffer.
+
+    pub fn block_size(&self) -> usize {
+        self.block_size as usize
+    }
+
+    pub fn count(&self) -> usize {
+        self.count as usize
+    }
+
+    pub fn offset(&self) -> usize {
+        self.offset as usize
+    }
+
+    /// Size in bytes of the bulk region described by this run
+    pub fn bulk_size(&self) -> usize {
+        self.length as usize
+    }
+
+    /// Checksum of the bulk region
+    pub fn checksum(&self) -> u32 {
+        self.checksum
+    }
+
+    /// Offset of the extension region, it directly follows the bulk region
+    pub fn extension_offset(&self) -> usize {
+        (self.offset + self.length) as usize
+    }
+
+    /// Size in bytes of the extension region, zero before version 3
+    pub fn extension_size(&self) -> usize {
+        self.ext_length as usize
+    }
+
+    /// Checksum of the extension region
+    pub fn extension_checksum(&self) -> u32 {
+        self.ext_checksum
+    }
+
+    /// End of the bulk and extension regions, checked by RunDesc::validate
+    fn end(&self) -> u64 {
+        self.offset + self.length + self.ext_length
+    }
+
+    /// Decode the descriptor at `offset` in `buf`, checking its bulk region against the whole buffer
+    fn from_buf_at(buf: &[u8], offset: usize, version: u32) -> Result<RunDesc> {
+        let desc_buf = buf.get(offset..).unwrap_or_default();
+        let mut desc = Self::decode(desc_buf, version).map_err(|err| match err {
+            Error::Truncated { needed, available } => Error::Truncated {
+                needed: offset + needed,
+                available: offset + available,
+            },
+            err => err,
+        })?;
+        if version == Header::LEGACY_VERSION && desc.offset.checked_add(desc.length).is_none_or(|end| end > buf.len() as u64) {
+            return Err(format!(
+                "Run for {}-byte blocks has no block data, the original version 1 writer stored only the run table",
+                desc.block_size
+            )
+            .into());
+        }
+        desc.validate(buf.len(), version)?;
+        if version == Header::LEGACY_VERSION {
+            // Legacy files store no checksums, the regions are taken as they are
+            desc.checksum = hash::checksum(&buf[desc.offset()..desc.offset() + desc.bulk_size()]);
+        }
+        Ok(desc)
     }
 
-    fn from_buf<'a>(buf: &'a [u8]) -> Result<&'a RunDesc> {
-        let ptr = buf as *const [u8];
-        let ptr = ptr.cast::<RunDesc>();
-        let blockdesc: Option<&'a RunDesc> = unsafe { ptr.as_ref() };
-        let blockdesc = blockdesc.ok_or_else(|| Error::from("Pointer conversion failed"))?;
-        blockdesc.validate(buf.len())
+    fn from_buf(buf: &[u8], version: u32) -> Result<RunDesc> {
+        let desc = Self::decode(buf, version)?;
+        desc.validate(buf.len(), version)?;
+        Ok(desc)
     }
 
-    fn validate(&self, buffer_length: usize) -> Result<&Self> {
-        let total_size = self.block_size * self.count;
-        let buffer_length = buffer_length as u32;
-        if self.offset + total_size > buffer_length {
-            let count = self.count;
-            let size = self.block_size;
-            Err(format!("Blocklist ({count} blocks at {size} bytes each) would overrun buffer ({buffer_length} bytes)").into())
+    fn decode(buf: &[u8], version: u32) -> Result<RunDesc> {
+        let mut decoder = Decoder::new(buf);
+        if version == Header::LEGACY_VERSION {
+            let block_size = u64::from(decoder.u32()?);
+            let count = u64::from(decoder.u32()?);
+            let offset = u64::from(decoder.u32()?) + Header::encoded_size(Header::LEGACY_VERSION) as u64;
+            let length = block_size
+                .checked_add(Block::HASH_SIZE as u64)
+                .and_then(|stride| stride.checked_mul(count))
+                .ok_or(Error::Overflow(count))?;
+            return Ok(Self {
+                kind: RunKind::Fixed as u32,
+                block_size,
+                count,
+                offset,
+                length,
+                checksum: hash::checksum(&[]),
+                ext_length: 0,
+                ext_checksum: hash::checksum(&[]),
+            });
+        }
+        let mut desc = Self {
+            kind: decoder.u32()?,
+            block_size: decoder.word(version)?,
+            count: decoder.word(version)?,
+            offset: decoder.word(version)?,
+            length: decoder.word(version)?,
+            checksum: decoder.u32()?,
+            ext_length: 0,
+            ext_checksum: hash::checksum(&[]),
+        };
+        if Header::has_features(version) {
+            desc.ext_length = decoder.word(version)?;
+            desc.ext_checksum = decoder.u32()?;
+        }
+        Ok(desc)
+    }
+
+    fn validate(&self, buffer_length: usize, version: u32) -> Result<&Self> {
+        let count = self.count;
+        let size = self.block_size;
+        let length = self.length;
+        let flags = self.kind & !Self::KIND_MASK;
+        if flags & !Self::COMPRESSED != 0 || (flags != 0 && !Header::has_features(version)) {
+            return Err(format!("Unknown run flags {flags:#x}").into());
+        }
+        let min_length = match RunKind::try_from(self.kind & Self::KIND_MASK)? {
+            // Compressed lengths are checked against the chunk index
+            _ if self.is_compressed() => 0,
+            RunKind::Fixed => {
+                let fixed_length = size
+                    .checked_add(Block::HASH_SIZE as u64)
+                    .and_then(|stride| stride.checked_mul(count));
+                if fixed_length != Some(length) {
+                    return Err(format!("Blocklist ({count} blocks at {size} bytes each) does not fit its length ({length} bytes)").into());
+                }
+                length
+            }
+            RunKind::Heap => (HeapEntry::encoded_size(version) as u64)
+                .checked_mul(count)
+                .ok_or(Error::Overflow(count))?,
+        };
+        let end = self
+            .offset
+            .checked_add(length)
+            .and_then(|end| end.checked_add(self.ext_length))
+            .ok_or(Error::Overflow(self.offset))?;
+        if length < min_length {
+            Err(format!("Heap ({count} blocks) index does not fit its length ({length} bytes)").into())
+        } else if end > buffer_length as u64 {
+            Err(format!("Blocklist ({count} blocks, {length} bytes) would overrun buffer ({buffer_length} bytes)").into())
         } else {
+            to_usize(size)?;
             Ok(self)
         } 
     }
 
-    fn write_out<W: Write>(&self, writer: &mut W) -> Result<usize> {
-        let mut size = writer.write(&self.block_size.to_le_bytes())?;
-        size += writer.write(&self.count.to_le_bytes())?;
-        size += writer.write(&self.offset.to_le_bytes())?;
-        Ok(size)
+    fn write_out<W: Write>(&self, writer: &mut W, version: u32) -> Result<usize> {
+        if version == Header::LEGACY_VERSION {
+            return Err(Error::InvalidVersion);
+        }
+        writer.write_all(&self.kind.to_le_bytes())?;
+        write_word(writer, self.block_size, version)?;
+        write_word(writer, self.count, version)?;
+        write_word(writer, self.offset, version)?;
+        write_word(writer, self.length, version)?;
+        writer.write_all(&self.checksum.to_le_bytes())?;
+        if Header::has_features(version) {
+            write_word(writer, self.ext_length, version)?;
+            writer.write_all(&self.ext_checksum.to_le_bytes())?;
+        } else if self.ext_length != 0 {
+            return Err(format!("Version {version} files can't hold run extensions").into());
+        }
+        Ok(Self::encoded_size(version))
     }
 }
 
-impl<'a> TryFrom<&'a [u8]> for &'a RunDesc {
+/// Iterator over the run table of a pressed file
+///
+/// Parses `Header::blocklist_size` descriptors starting right after the
+/// header. Besides validating each descriptor on its own, it checks that the
+/// bulk regions start after the table, are laid out in table order without
+/// overlapping, and that fixed runs come in strictly ascending block size
+/// order followed by at most one heap run. The latest run table of an
+/// appendable file lists the runs of every append in turn, so only the
+/// regions' order is checked there.
+pub struct RunTable<'a> {
+    buf: &'a [u8],
+    version: u32,
+    remaining: usize,
+    desc_offset: usize,
+    // End of the previous run's bulk and extension regions, where the next run may start at the earliest
+    bulk_end: u64,
+    previous: Option<RunDesc>,
+    // Whether block sizes may start over, as they do with every append
+    segmented: bool,
+}
+
+impl<'a> RunTable<'a> {
+    pub fn new(buf: &'a [u8], header: &Header) -> Self {
+        let bulk_end = header.table_range().map_or(u64::MAX, |range| range.end as u64);
+        Self {
+            buf,
+            version: header.version(),
+            remaining: header.blocklist_size(),
+            desc_offset: header.size(),
+            bulk_end,
+            previous: None,
+            segmented: false,
+        }
+    }
+
+    /// Table of the `count` runs at `offset` an appendable file's footer points to
+    pub(crate) fn appended(buf: &'a [u8], header: &Header, offset: usize, count: usize) -> Self {
+        Self {
+            remaining: count,
+            desc_offset: offset,
+            segmented: true,
+            ..Self::new(buf, header)
+        }
+    }
+
+    fn check_order(&self, desc: &RunDesc) -> Result<()> {
+        let offset = desc.offset;
+        if offset < self.bulk_end {
+            return Err(format!("Run at {offset} overlaps the run table or the previous run ending at {}", self.bulk_end).into());
+        }
+        let Some(previous) = self.previous.filter(|_| !self.segmented) else {
+            return Ok(());
+        };
+        match (previous.kind(), desc.kind()) {
+            (RunKind::Heap, _) => Err("Heap run must be the last run".into()),
+            (RunKind::Fixed, RunKind::Fixed) if desc.block_size <= previous.block_size => {
+                Err(format!("Block size {} follows {}, sizes must be unique and ascending", desc.block_size, previous.block_size).into())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Iterator for RunTable<'_> {
+    type Item = Result<RunDesc>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let result = RunDesc::from_buf_at(self.buf, self.desc_offset, self.version).and_then(|desc| {
+            self.check_order(&desc)?;
+            Ok(desc)
+        });
+        match result {
+            Ok(desc) => {
+                self.remaining -= 1;
+                self.desc_offset += RunDesc::encoded_size(self.version);
+                // Can't overflow, checked by RunDesc::validate
+                self.bulk_end = desc.end();
+                self.previous = Some(desc);
+            }
+            // Stop after the first bad descriptor, its successors can't be trusted
+            Err(_) => self.remaining = 0,
+        }
+        Some(result)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.remaining))
+    }
+}
+
+/// Index entry of a heap run, locating one block in the run's data region
+#[derive(Clone, Copy, Debug)]
+struct HeapEntry {
+    hash: u32,
+    offset: u64,
+    length: u64,
+}
+
+impl HeapEntry {
+    const fn encoded_size(version: u32) -> usize {
+        size_of::<u32>() + 2 * word_size(version)
+    }
+
+    fn from_buf(buf: &[u8], version: u32) -> Result<Self> {
+        let mut decoder = Decoder::new(buf);
+        Ok(Self {
+            hash: decoder.u32()?,
+            offset: decoder.word(version)?,
+            length: decoder.word(version)?,
+        })
+    }
+
+    fn write_out<W: Write>(&self, writer: &mut W, version: u32) -> Result<usize> {
+        writer.write_all(&self.hash.to_le_bytes())?;
+        write_word(writer, self.offset, version)?;
+        write_word(writer, self.length, version)?;
+        Ok(Self::encoded_size(version))
+    }
+}
+
+/// Decodes a descriptor in the newest format version
+impl TryFrom<&[u8]> for RunDesc {
     type Error = Error;
-    fn try_from(value: &'a [u8]) -> Result<&'a RunDesc> {
-        RunDesc::from_buf(value)
+    fn try_from(value: &[u8]) -> Result<RunDesc> {
+        RunDesc::from_buf(value, Header::VERSION)
+    }
+}
+
+/// Identifies a block added to a [`Collector`]
+///
+/// Ids stay valid for the lifetime of the collector. They reflect insertion
+/// order, not the position of the block in the pressed file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct BlockId {
+    pub block_size: usize,
+    pub index: usize,
+}
+
+/// Outcome of [`Collector::add`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Added {
+    /// The block was not in the collector yet
+    New(BlockId),
+    /// The same bytes were already added, the id is the one of the earlier block
+    Duplicate(BlockId),
+}
+
+impl Added {
+    pub fn id(&self) -> BlockId {
+        match self {
+            Added::New(id) | Added::Duplicate(id) => *id,
+        }
+    }
+
+    pub fn is_new(&self) -> bool {
+        matches!(self, Added::New(_))
+    }
+}
+
+/// Configures a [`Collector`], everything set here is recorded in the pressed [`Header`]
+#[derive(Clone, Debug)]
+pub struct CollectorBuilder {
+    max_size: usize,
+    seed: u32,
+    dedup: bool,
+    alignment: usize,
+    version: u32,
+    heap_threshold: Option<usize>,
+    perfect_hash: bool,
+    filter: Option<f64>,
+    codec: Option<Codec>,
+    chunk_size: usize,
+    appendable: bool,
+    spill_dir: Option<PathBuf>,
+}
+
+impl Default for CollectorBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CollectorBuilder {
+    pub fn new() -> Self {
+        Self {
+            max_size: Collector::DEFAULT_MAX_SIZE,
+            seed: hash::DEFAULT_SEED,
+            dedup: true,
+            alignment: 1,
+            version: Header::VERSION,
+            heap_threshold: None,
+            perfect_hash: false,
+            filter: None,
+            codec: None,
+            chunk_size: Compression::DEFAULT_CHUNK_SIZE,
+            appendable: false,
+            spill_dir: None,
+        }
+    }
+
+    /// Settings of the collector `reader`'s file was pressed with, as far as the file records them
+    ///
+    /// The version, seed, maximum block size, dedup setting, alignment, heap
+    /// threshold, perfect hash index, filter, compression and whether the file
+    /// is appendable carry over. A collector built from these can
+    /// [`Collector::append`] to the file. Files in the original version 1
+    /// layout get the current version, since that layout is only read.
+    pub fn from_reader(reader: &Reader) -> Result<Self> {
+        let header = reader.header();
+        let max_size = usize::try_from(header.max_size()).map_err(|_| Error::Overflow(header.max_size()))?;
+        let version = match header.version() {
+            Header::LEGACY_VERSION => Header::VERSION,
+            version => version,
+        };
+        let mut builder = Self::new()
+            .version(version)
+            .max_size(max_size)
+            .seed(header.seed())
+            .dedup(header.dedup())
+            .alignment(header.alignment())
+            .perfect_hash(header.optional_features().contains(Features::PERFECT_HASH))
+            .appendable(header.required_features().contains(Features::APPENDABLE));
+        if let Some(rate) = reader.shelves().find_map(|shelf| shelf.filter_rate()) {
+            builder = builder.filter(rate);
+        }
+        if let Some(codec) = reader.shelves().find_map(|shelf| shelf.codec()) {
+            builder = builder.compression(codec);
+        }
+        if let Some(heap) = reader.heap() {
+            builder = builder.heap_threshold(heap.block_size());
+        }
+        Ok(builder)
+    }
+
+    /// Largest block accepted by [`Collector::add`]
+    pub fn max_size(mut self, max_size: usize) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    /// Seed for the block hashes
+    pub fn seed(mut self, seed: u32) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Drop duplicate blocks in [`Collector::add`], enabled by default
+    pub fn dedup(mut self, dedup: bool) -> Self {
+        self.dedup = dedup;
+        self
+    }
+
+    /// Align the start of every shelf's bulk region to this many bytes, must be a power of two
+    pub fn alignment(mut self, alignment: usize) -> Self {
+        self.alignment = alignment;
+        self
+    }
+
+    /// File format version to press
+    pub fn version(mut self, version: u32) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// Keep blocks of at least `threshold` bytes in a single variable-length heap shelf
+    /// instead of one fixed-size shelf per length
+    pub fn heap_threshold(mut self, threshold: usize) -> Self {
+        self.heap_threshold = Some(threshold);
+        self
+    }
+
+    /// Store a minimal perfect hash index with every shelf for constant-time lookups,
+    /// needs version 3 or later
+    pub fn perfect_hash(mut self, perfect_hash: bool) -> Self {
+        self.perfect_hash = perfect_hash;
+        self
+    }
+
+    /// Store a Bloom filter with every shelf, so most lookups of missing blocks
+    /// don't touch the shelf's entries, needs version 3 or later
+    ///
+    /// `false_positive_rate` is the share of missing blocks that get past the
+    /// filter, between 0 and 1 exclusive.
+    pub fn filter(mut self, false_positive_rate: f64) -> Self {
+        self.filter = Some(false_positive_rate);
+        self
+    }
+
+    /// Compress shelves with `codec` in chunks that decompress independently,
+    /// needs version 3 or later and the codec's cargo feature
+    ///
+    /// Shelves compression doesn't make smaller are stored as they are.
+    pub fn compression(mut self, codec: Codec) -> Self {
+        self.codec = Some(codec);
+        self
+    }
+
+    /// Approximate uncompressed size of a compressed chunk, reading a block decompresses one
+    pub fn chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    /// Press files that blocks can be appended to later with [`Collector::append`],
+    /// needs version 3 or later
+    pub fn appendable(mut self, appendable: bool) -> Self {
+        self.appendable = appendable;
+        self
+    }
+
+    /// Directory for the temporary files of a [`SpillingCollector`], the system's temporary directory by default
+    pub fn spill_dir<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.spill_dir = Some(dir.into());
+        self
+    }
+
+    /// Build a collector that keeps about `memory_budget` bytes of blocks in memory
+    /// and spills the rest to temporary files, see [`SpillingCollector`]
+    pub fn build_spilling(mut self, memory_budget: usize) -> Result<SpillingCollector> {
+        let dir = self.spill_dir.take();
+        Ok(SpillingCollector::new(self.build()?, memory_budget, dir))
+    }
+
+    pub fn build(self) -> Result<Collector> {
+        if !Header::is_supported(self.version) {
+            return Err(Error::InvalidVersion);
+        }
+        if self.perfect_hash && !Header::has_features(self.version) {
+            return Err(format!("Version {} files can't hold a perfect hash index", self.version).into());
+        }
+        if let Some(rate) = self.filter {
+            if !Header::has_features(self.version) {
+                return Err(format!("Version {} files can't hold filters", self.version).into());
+            }
+            if !(rate > 0.0 && rate < 1.0) {
+                return Err(format!("Invalid false positive rate {rate}").into());
+            }
+        }
+        if let Some(codec) = self.codec {
+            if !Header::has_features(self.version) {
+                return Err(format!("Version {} files can't hold compressed shelves", self.version).into());
+            }
+            if !codec.is_available() {
+                return Err(codec.unavailable());
+            }
+        }
+        if self.appendable && !Header::has_features(self.version) {
+            return Err(format!("Version {} files can't be appended to", self.version).into());
+        }
+        let compression = self.codec.map(|codec| Compression {
+            codec,
+            chunk_size: self.chunk_size.max(1),
+            chunk_entries: None,
+        });
+        // Version 1 files store sizes in 32 bits
+        let fits = |size: usize| Header::wide(self.version) || u32::try_from(size).is_ok();
+        if !fits(self.max_size) {
+            return Err(Error::TooLarge(self.max_size));
+        }
+        if !self.alignment.is_power_of_two() || u32::try_from(self.alignment).is_err() {
+            return Err(format!("Invalid alignment {}", self.alignment).into());
+        }
+        let heap = match self.heap_threshold {
+            Some(threshold) if !fits(threshold) => {
+                return Err(Error::TooLarge(threshold));
+            }
+            Some(threshold) => Some(Shelf::heap(threshold)),
+            None => None,
+        };
+        Ok(Collector {
+            max_size: self.max_size,
+            seed: self.seed,
+            dedup: self.dedup,
+            alignment: self.alignment,
+            version: self.version,
+            values: false,
+            perfect_hash: self.perfect_hash,
+            filter: self.filter,
+            compression,
+            appendable: self.appendable,
+            shelves: BTreeMap::new(),
+            heap,
+        })
     }
 }
 
 pub struct Collector {
     max_size: usize,
+    seed: u32,
+    dedup: bool,
+    alignment: usize,
+    version: u32,
+    // Whether blocks were inserted with values, every block then gets one
+    values: bool,
+    perfect_hash: bool,
+    // False positive rate of the shelf filters, if enabled
+    filter: Option<f64>,
+    compression: Option<Compression>,
+    // Whether pressed files end in a footer, so blocks can be appended
+    appendable: bool,
     shelves: BTreeMap<usize, Shelf>,
+    // Variable-length shelf for blocks at or above its threshold, if enabled
+    heap: Option<Shelf>,
+}
+
+impl Debug for Collector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Collector")
+            .field("max_size", &self.max_size)
+            .field("seed", &format_args!("{:x}", self.seed))
+            .field("dedup", &self.dedup)
+            .field("shelves", &self.runs().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl Default for Collector {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Collector {
     pub const DEFAULT_MAX_SIZE: usize = 40;
     pub fn new() -> Self {
-        Collector { 
-            max_size: Self::DEFAULT_MAX_SIZE, 
+        Collector {
+            max_size: Self::DEFAULT_MAX_SIZE,
+            seed: hash::DEFAULT_SEED,
+            dedup: true,
+            alignment: 1,
+            version: Header::VERSION,
+            values: false,
+            perfect_hash: false,
+            filter: None,
+            compression: None,
+            appendable: false,
             shelves: BTreeMap::new(),
+            heap: None,
         }
     }
 
-    pub fn add<T: AsRef<[u8]>>(&mut self, data: T) -> Result<()> {
-        let buf = data.as_ref();
+    pub fn builder() -> CollectorBuilder {
+        CollectorBuilder::new()
+    }
+
+    pub fn add<T: AsRef<[u8]>>(&mut self, data: T) -> Result<Added> {
+        self.push(data.as_ref(), None)
+    }
+
+    /// Add `key` as a block with `value` attached, see [`Reader::get`]
+    ///
+    /// Blocks added without a value get an empty one. With dedup enabled,
+    /// inserting a key again replaces its value. Needs version 3 or later.
+    pub fn insert<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, value: V) -> Result<Added> {
+        if !Header::has_features(self.version) {
+            return Err(format!("Version {} files can't hold values", self.version).into());
+        }
+        let added = self.push(key.as_ref(), Some(value.as_ref()))?;
+        self.values = true;
+        Ok(added)
+    }
+
+    fn push(&mut self, buf: &[u8], value: Option<&[u8]>) -> Result<Added> {
         let block_len = buf.len();
         if block_len > self.max_size {
             return Err(Error::TooLarge(block_len));
         }
-        let block = Block::new(buf);
-        let shelf = self.shelves.entry(block_len).or_insert(Shelf::new(block_len));
-        shelf.add_block(block);
-        Ok(())
+        let mut block = Block::new(buf, self.seed);
+        let shelf = match self.heap.as_mut() {
+            Some(heap) if block_len >= heap.block_size => heap,
+            _ => self.shelves.entry(block_len).or_insert_with(|| Shelf::new(block_len)),
+        };
+        if self.dedup {
+            if let Some(index) = shelf.position(&block) {
+                if let Some(value) = value {
+                    shelf.blocks[index].value = Some(value.to_vec());
+                }
+                return Ok(Added::Duplicate(BlockId { block_size: block_len, index }));
+            }
+        }
+        block.value = value.map(<[u8]>::to_vec);
+        let index = shelf.add_block(block);
+        Ok(Added::New(BlockId { block_size: block_len, index }))
     }
 
-    pub fn press<F: Write>(&self, writer: &mut F) -> Result<()> {
-        let mut current_offset = 0;
-        let mut bulk_offset = 0;
-        let header = Header::new(self.shelves.len());
-        current_offset += header.write_out(writer)?;
-        bulk_offset = self.shelves.len() * size_of::<RunDesc>();
-        for (_size, shelf) in self.shelves.iter() {
-            let run_desc = shelf.create_run_desc(bulk_offset);
-            bulk_offset += shelf.bulk_size();
-            current_offset += run_desc.write_out(writer)?;
+    /// Longest block the collector takes, see [`CollectorBuilder::max_size`]
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    /// Number of blocks held, duplicates included if dedup is disabled
+    pub fn len(&self) -> usize {
+        self.runs().map(|shelf| shelf.blocks.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Write the collected blocks out, returning the number of bytes written
+    ///
+    /// The output depends only on the blocks held and the builder settings:
+    /// shelves go by block size, entries within a shelf by hash, then bytes,
+    /// then value, padding is zero bytes and every field is little-endian.
+    /// Adding the same blocks in any order presses the same bytes on every
+    /// platform, as long as the seed is the same, [`hash::DEFAULT_SEED`] unless
+    /// set. The one exception is a key inserted more than once, which keeps the
+    /// value inserted last. Compressed shelves also need the same codec version.
+    pub fn press<F: Write>(&self, writer: &mut F) -> Result<usize> {
+        // Bulk regions are encoded up front, their checksums go into the run table
+        let shelves = self.runs().map(|shelf| shelf.encode(self)).collect::<Result<Vec<_>>>()?;
+        self.write_shelves(&shelves, writer)
+    }
+
+    /// Like [`Collector::press`], but encodes and writes shelves on up to `threads` threads at once
+    ///
+    /// Each shelf is written to its place in `file` with positioned writes,
+    /// the file ends up holding the same bytes `press` writes and is truncated
+    /// to them. Returns the number of bytes written.
+    pub fn press_parallel(&self, file: &File, threads: usize) -> Result<usize> {
+        let runs: Vec<&Shelf> = self.runs().collect();
+        let shelves = parallel::map(&runs, threads, |shelf| shelf.encode(self))?;
+        let layout = self.layout(&shelves)?;
+        parallel::write_all_at(file, &layout.head, 0)?;
+        let placed: Vec<_> = shelves.iter().zip(layout.positions.iter()).collect();
+        parallel::map(&placed, threads, |(encoded, (start, offset))| {
+            parallel::write_all_at(file, &vec![0; offset - start], *start)?;
+            parallel::write_all_at(file, &encoded.bulk, *offset)?;
+            parallel::write_all_at(file, &encoded.extension, offset + encoded.bulk.len())
+        })?;
+        parallel::write_all_at(file, &layout.tail, layout.size - layout.tail.len())?;
+        file.set_len(layout.size as u64)?;
+        Ok(layout.size)
+    }
+
+    /// Append the blocks to `file`, pressed by a collector with the same settings
+    /// and [`CollectorBuilder::appendable`], returning what was written
+    ///
+    /// New shelves go after the data already in the file, followed by a run
+    /// table listing the shelves of every append and a footer pointing to it.
+    /// Nothing in the file is rewritten, an append that fails midway leaves the
+    /// file unreadable until it's truncated back to its previous size. With
+    /// dedup, blocks the file holds already are skipped and counted in
+    /// [`AppendStats::skipped`], and keys the file holds with a different
+    /// value are an error, since appending can't replace a value.
+    pub fn append(&self, file: &mut File) -> Result<AppendStats> {
+        append::append(self, file)
+    }
+
+    /// Write a file holding `shelves`, encoded with this collector's settings and in run table order
+    fn write_shelves<F: Write>(&self, shelves: &[EncodedShelf], writer: &mut F) -> Result<usize> {
+        let layout = self.layout(shelves)?;
+        Self::write_layout(&layout, shelves, writer)
+    }
+
+    /// Write `shelves` out where `layout` places them
+    fn write_layout<F: Write>(layout: &Layout, shelves: &[EncodedShelf], writer: &mut F) -> Result<usize> {
+        writer.write_all(&layout.head)?;
+        for (encoded, (start, offset)) in shelves.iter().zip(layout.positions.iter()) {
+            writer.write_all(&vec![0; offset - start])?;
+            writer.write_all(&encoded.bulk)?;
+            writer.write_all(&encoded.extension)?;
+        }
+        writer.write_all(&layout.tail)?;
+        Ok(layout.size)
+    }
+
+    /// Place the header, run table and `shelves` in a file
+    fn layout(&self, shelves: &[EncodedShelf]) -> Result<Layout> {
+        let mut header = Header::for_collector(self, shelves.len());
+        for encoded in shelves.iter() {
+            if let Some(codec) = encoded.codec {
+                header.required_features = header.required_features.union(codec.feature());
+            }
+        }
+        let mut table = Vec::new();
+        let mut positions = Vec::with_capacity(shelves.len());
+        // Run offsets are absolute, the bulk regions start right after the run table
+        let mut bulk_offset = header.table_range()?.end;
+        for encoded in shelves.iter() {
+            let start = bulk_offset;
+            bulk_offset = bulk_offset
+                .checked_next_multiple_of(self.alignment)
+                .ok_or(Error::Overflow(bulk_offset as u64))?;
+            positions.push((start, bulk_offset));
+            let run_desc = encoded.run_desc(bulk_offset);
+            bulk_offset = bulk_offset
+                .checked_add(encoded.bulk.len() + encoded.extension.len())
+                .ok_or(Error::Overflow(bulk_offset as u64))?;
+            run_desc.write_out(&mut table, self.version)?;
+        }
+        let header = Header {
+            table_checksum: hash::checksum(&table),
+            ..header
+        };
+        let mut head = Vec::with_capacity(header.size() + table.len());
+        header.write_out(&mut head)?;
+        head.extend_from_slice(&table);
+        // The footer of a freshly pressed file points to the run table up front
+        let mut tail = Vec::new();
+        if self.appendable {
+            Footer::new(header.size(), shelves.len(), header.required_features, &table).write_out(&mut tail)?;
         }
+        Ok(Layout {
+            head,
+            positions,
+            size: bulk_offset + tail.len(),
+            tail,
+        })
+    }
 
-        for (_size, shelf) in self.shelves.iter() {
-            
+    /// Drop every block, keeping the settings
+    fn clear(&mut self) {
+        self.shelves.clear();
+        if let Some(heap) = self.heap.as_mut() {
+            heap.blocks.clear();
+            heap.by_hash.clear();
         }
-        Ok(())
+    }
+
+    /// The non-empty shelf of `kind` holding blocks of `block_size` bytes
+    fn shelf(&self, kind: RunKind, block_size: usize) -> Option<&Shelf> {
+        self.runs().find(|shelf| shelf.kind == kind && shelf.block_size == block_size)
+    }
+
+    /// Non-empty shelves in run table order: fixed shelves by size, then the heap
+    fn runs(&self) -> impl Iterator<Item = &Shelf> {
+        let heap = self.heap.iter().filter(|heap| !heap.blocks.is_empty());
+        self.shelves.values().chain(heap)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Compression {
+    codec: Codec,
+    chunk_size: usize,
+    // Entries per chunk regardless of their size, to reproduce an existing shelf
+    chunk_entries: Option<usize>,
+}
+
+impl Compression {
+    const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+}
+
+/// Regions of a shelf ready to be written
+struct EncodedShelf {
+    kind: RunKind,
+    block_size: usize,
+    count: usize,
+    bulk: Region,
+    extension: Region,
+    // Codec the bulk region is compressed with, if it is
+    codec: Option<Codec>,
+}
+
+impl EncodedShelf {
+    /// Descriptor for the regions placed at `offset`
+    fn run_desc(&self, offset: usize) -> RunDesc {
+        let compressed = if self.codec.is_some() { RunDesc::COMPRESSED } else { 0 };
+        RunDesc {
+            kind : self.kind as u32 | compressed,
+            block_size : self.block_size as u64,
+            count : self.count as u64,
+            offset : offset as u64,
+            length : self.bulk.len() as u64,
+            checksum : hash::checksum(&self.bulk),
+            ext_length : self.extension.len() as u64,
+            ext_checksum : hash::checksum(&self.extension),
+        }
+    }
+}
+
+/// Where everything goes in a pressed file
+struct Layout {
+    // Encoded header and run table, at the start of the file
+    head: Vec<u8>,
+    // Start of every shelf's padding and of its bulk region
+    positions: Vec<(usize, usize)>,
+    // Footer of an appendable file, at the end of the file
+    tail: Vec<u8>,
+    // Size of the whole file
+    size: usize,
+}
+
+/// Bytes of an encoded region, built in memory or spooled to a temporary file
+enum Region {
+    Memory(Vec<u8>),
+    Mapped(Mmap),
+}
+
+impl Region {
+    /// Map the whole of a spooled region
+    fn from_file(file: &File) -> Result<Self> {
+        // Empty files can't be mapped
+        if file.metadata()?.len() == 0 {
+            return Ok(Region::Memory(Vec::new()));
+        }
+        Ok(Region::Mapped(unsafe { Mmap::map(file) }?))
+    }
+}
+
+impl Deref for Region {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Region::Memory(bytes) => bytes,
+            Region::Mapped(map) => map,
+        }
+    }
+}
+
+/// Keys of the per-shelf indexes a collector is set up to build, gathered entry by entry
+struct Indexes {
+    // Stored hashes for the perfect hash index
+    hashes: Option<Vec<u32>>,
+    // False positive rate, seed and keys of the filter
+    filter: Option<(f64, u32, Vec<u64>)>,
+}
+
+impl Indexes {
+    fn new(collector: &Collector) -> Self {
+        Self {
+            hashes: collector.perfect_hash.then(Vec::new),
+            filter: collector.filter.map(|rate| (rate, filter::seed(collector.seed), Vec::new())),
+        }
+    }
+
+    /// Add the next entry in on-disk order
+    fn push(&mut self, hash: u32, data: &[u8]) {
+        if let Some(hashes) = self.hashes.as_mut() {
+            hashes.push(hash);
+        }
+        if let Some((_, seed, keys)) = self.filter.as_mut() {
+            keys.push(filter::key(hash, *seed, data));
+        }
+    }
+
+    /// Extension records of the indexes, the perfect hash before the filter
+    fn records(self) -> Result<Vec<(u32, Vec<u8>)>> {
+        let mut records = Vec::new();
+        if let Some(payload) = self.hashes.map(|hashes| mph::build(&hashes)).transpose()?.flatten() {
+            records.push((Extensions::PERFECT_HASH, payload));
+        }
+        if let Some((rate, seed, keys)) = self.filter {
+            records.push((Extensions::FILTER, filter::build(&keys, rate, seed)));
+        }
+        Ok(records)
     }
 }
 
 struct Shelf {
+    kind: RunKind,
+    // Exact block length for fixed shelves, minimum length for the heap
     block_size: usize,
     blocks: Vec<Block>,
+    // Block indexes by hash, for finding duplicates
+    by_hash: HashMap<u32, Vec<usize>>,
 }
 
 impl Debug for Shelf {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Shelf")
+            .field("kind", &self.kind)
             .field("block_size", &self.block_size)
             .field("blocks", &self.blocks.len())
             .finish()
@@ -193,56 +1519,184 @@ impl Debug for Shelf {
 impl Shelf {
     fn new(block_size: usize) -> Self {
         Self {
+            kind: RunKind::Fixed,
             block_size,
             blocks: Vec::new(),
+            by_hash: HashMap::new(),
         }
     }
 
-    fn add_block(&mut self, block: Block) {
-        assert_eq!(self.block_size, block.data.len());
+    fn heap(threshold: usize) -> Self {
+        Self {
+            kind: RunKind::Heap,
+            ..Self::new(threshold)
+        }
+    }
+
+    fn add_block(&mut self, block: Block) -> usize {
+        match self.kind {
+            RunKind::Fixed => assert_eq!(self.block_size, block.data.len()),
+            RunKind::Heap => assert!(self.block_size <= block.data.len()),
+        }
+        let index = self.blocks.len();
+        self.by_hash.entry(block.hash).or_default().push(index);
         self.blocks.push(block); 
+        index
     }
 
-    fn create_run_desc(&self, offset: usize) -> RunDesc {
-        RunDesc {
-            block_size : self.block_size as u32,
-            count : self.blocks.len() as u32,
-            offset : offset as u32,
+    fn position(&self, block: &Block) -> Option<usize> {
+        self.by_hash
+            .get(&block.hash)?
+            .iter()
+            .copied()
+            .find(|index| self.blocks[*index].data == block.data)
+    }
+
+    /// Encode the bulk and extension regions, as `collector` is set up to
+    fn encode(&self, collector: &Collector) -> Result<EncodedShelf> {
+        let version = collector.version;
+        let blocks = self.sorted_blocks();
+        let mut bulk = Vec::with_capacity(self.bulk_size(version));
+        self.write_blocks(&blocks, &mut bulk, version)?;
+        let mut records = self.extension_records(&blocks, collector)?;
+        let mut codec = None;
+        if let Some(compression) = collector.compression {
+            // Shelves that don't shrink are left uncompressed
+            let mut chunks = Vec::new();
+            let (_, index) = compress::compress_run(self.kind, blocks.len(), &bulk, compression, version, &mut chunks)?;
+            if chunks.len() < bulk.len() {
+                bulk = chunks;
+                records.push((Extensions::CHUNKS, index));
+                codec = Some(compression.codec);
+            }
         }
+        if !Header::has_features(version) && !records.is_empty() {
+            return Err(format!("Version {version} files can't hold run extensions").into());
+        }
+        let mut extension = Vec::new();
+        for (tag, payload) in records.iter() {
+            extension::write_record(&mut extension, *tag, payload)?;
+        }
+        Ok(EncodedShelf {
+            kind: self.kind,
+            block_size: self.block_size,
+            count: blocks.len(),
+            bulk: Region::Memory(bulk),
+            extension: Region::Memory(extension),
+            codec,
+        })
     }
 
-    fn bulk_size(&self) -> usize {
-        if self.blocks.is_empty() {
-            0
-        } else {
-            self.blocks.len() * self.blocks[0].size()
+    /// Extension records `collector` is set up to store beside the blocks
+    fn extension_records(&self, blocks: &[&Block], collector: &Collector) -> Result<Vec<(u32, Vec<u8>)>> {
+        let mut records = Vec::new();
+        if collector.values {
+            let payload = ValueTable::encode(blocks.iter().map(|block| block.value()));
+            records.push((Extensions::VALUES, payload));
+        }
+        let mut indexes = Indexes::new(collector);
+        for block in blocks.iter() {
+            indexes.push(block.hash, &block.data);
         }
+        records.extend(indexes.records()?);
+        Ok(records)
+    }
+
+    fn bulk_size(&self, version: u32) -> usize {
+        match self.kind {
+            RunKind::Fixed => self.blocks.iter().map(Block::size).sum(),
+            RunKind::Heap => self
+                .blocks
+                .iter()
+                .map(|block| HeapEntry::encoded_size(version) + block.data.len())
+                .sum(),
+        }
+    }
+
+    /// Blocks in on-disk order: by hash, ties broken by the block bytes, then the value
+    fn sorted_blocks(&self) -> Vec<&Block> {
+        let mut blocks: Vec<&Block> = self.blocks.iter().collect();
+        blocks.sort_unstable_by(|a, b| (a.hash, &a.data, &a.value).cmp(&(b.hash, &b.data, &b.value)));
+        blocks
+    }
+
+    /// Write `blocks` out as a bulk region of this shelf's kind
+    fn write_blocks<W: Write>(&self, blocks: &[&Block], writer: &mut W, version: u32) -> Result<usize> {
+        let mut size = 0;
+        match self.kind {
+            RunKind::Fixed => {
+                for block in blocks {
+                    size += block.write_out(writer)?;
+                }
+            }
+            RunKind::Heap => {
+                let mut data_offset = 0u64;
+                for block in blocks.iter() {
+                    let length = block.data.len() as u64;
+                    let entry = HeapEntry {
+                        hash: block.hash,
+                        offset: data_offset,
+                        length,
+                    };
+                    data_offset = data_offset.checked_add(length).ok_or(Error::Overflow(data_offset))?;
+                    size += entry.write_out(writer, version)?;
+                }
+                for block in blocks {
+                    writer.write_all(&block.data)?;
+                    size += block.data.len();
+                }
+            }
+        }
+        Ok(size)
     }
 }
 
+#[derive(Clone)]
 struct Block {
     hash: u32,
     data: Vec<u8>,
+    // Stored in the run's value table, not next to the block, None if added without one
+    value: Option<Vec<u8>>,
 }
 
 impl Block {
-    fn new(data: &[u8]) -> Self {
+    const HASH_SIZE: usize = size_of::<u32>();
+
+    fn new(data: &[u8], seed: u32) -> Self {
         Self {
-            hash: 0,
+            hash: hash::hash(seed, data),
             data: data.to_vec(),
+            value: None,
         }
     }
 
+    /// Value stored for the block, empty if it was added without one
+    fn value(&self) -> &[u8] {
+        self.value.as_deref().unwrap_or_default()
+    }
+
     fn size(&self) -> usize {
-        size_of::<u32>() + self.data.len()
+        Self::HASH_SIZE + self.data.len()
+    }
+
+    /// Stored hash at the front of an on-disk block entry
+    fn read_hash(entry: &[u8]) -> Option<u32> {
+        Decoder::new(entry).u32().ok()
+    }
+
+    fn write_out<W: Write>(&self, writer: &mut W) -> Result<usize> {
+        writer.write_all(&self.hash.to_le_bytes())?;
+        writer.write_all(&self.data)?;
+        Ok(self.size())
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::{borrow::Cow, io::{Read, Seek}};
     use tempfile::tempfile;
-    use rand::{Rng, rngs::SmallRng, SeedableRng};
+    use rand::{Rng, rngs::SmallRng, SeedableRng, seq::SliceRandom};
 
     fn generate_test_data(size_count: Vec<(usize, usize)>, rng: &mut impl Rng) -> Vec<Vec<u8>> {
         let mut data = Vec::new();
@@ -267,6 +1721,115 @@ mod tests {
         println!("{rheader:#?}");
     }
 
+    #[test]
+    fn header_is_little_endian() {
+        let mut buf = Vec::new();
+        Header::new(2).write_out(&mut buf).unwrap();
+        assert_eq!(buf.len(), Header::encoded_size(Header::VERSION));
+        assert_eq!(&buf[..4], &[0xBB, 0x33, 0xAA, 0x55]);
+        assert_eq!(&buf[8..16], &[2, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(Header::from_buf(&buf).unwrap().blocklist_size(), 2);
+
+        let mut buf = Vec::new();
+        Header { version: 1, ..Header::new(2) }.write_out(&mut buf).unwrap();
+        assert_eq!(buf.len(), Header::encoded_size(1));
+        assert_eq!(&buf[8..12], &[2, 0, 0, 0]);
+        assert_eq!(Header::from_buf(&buf).unwrap().version(), 1);
+    }
+
+    #[test]
+    fn feature_flags() {
+        let encode = |required: u32, optional: u32| {
+            let mut buf = Vec::new();
+            let header = Header {
+                required_features: Features::from_bits(required),
+                optional_features: Features::from_bits(optional),
+                ..Header::new(0)
+            };
+            header.write_out(&mut buf).unwrap();
+            buf
+        };
+        let header = Header::from_buf(&encode(0, 0x8000_0001)).unwrap();
+        assert_eq!(header.optional_features().bits(), 0x8000_0001);
+        let result = Header::from_buf(&encode(0x8000_0000, 0));
+        assert!(matches!(result, Err(Error::UnsupportedFeatures(0x8000_0000))));
+
+        // Versions before 3 have no room for features
+        let mut buf = Vec::new();
+        let header = Header { version: 2, optional_features: Features::from_bits(1), ..Header::new(0) };
+        assert!(header.write_out(&mut buf).is_err());
+    }
+
+    fn encode_table(descs: &[RunDesc], total: usize) -> Vec<u8> {
+        let mut buf = Vec::new();
+        Header::new(descs.len()).write_out(&mut buf).unwrap();
+        for desc in descs {
+            desc.write_out(&mut buf, Header::VERSION).unwrap();
+        }
+        buf.resize(total, 0);
+        buf
+    }
+
+    #[test]
+    fn run_table_checks() {
+        let start = (Header::encoded_size(Header::VERSION) + 2 * RunDesc::encoded_size(Header::VERSION)) as u64;
+        let fixed = |block_size: u64, count: u64, offset: u64| RunDesc {
+            kind: RunKind::Fixed as u32,
+            block_size,
+            count,
+            offset,
+            length: block_size.wrapping_add(4).wrapping_mul(count),
+            checksum: 0,
+            ext_length: 0,
+            ext_checksum: 0,
+        };
+        let parse = |descs: &[RunDesc]| {
+            let buf = encode_table(descs, 256);
+            let header = Header::from_buf(&buf).unwrap();
+            RunTable::new(&buf, &header).collect::<Result<Vec<_>>>()
+        };
+
+        assert_eq!(parse(&[fixed(2, 3, start), fixed(4, 2, start + 18)]).unwrap().len(), 2);
+        // Overlapping bulk regions
+        assert!(parse(&[fixed(2, 3, start), fixed(4, 2, start + 17)]).is_err());
+        // Bulk region inside the run table
+        assert!(parse(&[fixed(2, 3, start - 1), fixed(4, 2, start + 18)]).is_err());
+        // Block sizes out of order or repeated
+        assert!(parse(&[fixed(4, 2, start), fixed(2, 3, start + 16)]).is_err());
+        assert!(parse(&[fixed(2, 3, start), fixed(2, 3, start + 18)]).is_err());
+        // Out of bounds
+        assert!(parse(&[fixed(2, 3, start), fixed(4, 200, start + 18)]).is_err());
+        // Sizes that overflow instead of being out of bounds
+        assert!(parse(&[fixed(u64::MAX - 2, 3, start), fixed(4, 2, start + 18)]).is_err());
+        assert!(parse(&[fixed(2, 3, u64::MAX - 10), fixed(4, 2, start + 18)]).is_err());
+        // Extension region running into the next run
+        let extended = RunDesc { ext_length: 2, ..fixed(2, 3, start) };
+        assert!(parse(&[extended, fixed(4, 2, start + 20)]).is_ok());
+        assert!(parse(&[extended, fixed(4, 2, start + 19)]).is_err());
+        assert!(parse(&[RunDesc { ext_length: u64::MAX, ..extended }]).is_err());
+        // Heap must come last
+        let heap = RunDesc { kind: RunKind::Heap as u32, block_size: 8, count: 0, offset: start, length: 0, checksum: 0, ext_length: 0, ext_checksum: 0 };
+        assert!(parse(&[heap, fixed(4, 2, start)]).is_err());
+        assert!(parse(&[fixed(2, 3, start), RunDesc { offset: start + 18, ..heap }]).is_ok());
+    }
+
+    #[test]
+    fn truncated_buffers() {
+        let mut buf = Vec::new();
+        Header::new(1).write_out(&mut buf).unwrap();
+        for length in 0..Header::encoded_size(Header::VERSION) {
+            let result = Header::from_buf(&buf[..length]);
+            assert!(matches!(result, Err(Error::Truncated { available, .. }) if available == length));
+        }
+        let desc = RunDesc { kind: 0, block_size: 1, count: 0, offset: 0, length: 0, checksum: 0, ext_length: 0, ext_checksum: 0 };
+        let mut buf = Vec::new();
+        desc.write_out(&mut buf, Header::VERSION).unwrap();
+        assert!(RunDesc::try_from(&buf[..]).is_ok());
+        let size = RunDesc::encoded_size(Header::VERSION);
+        let result = RunDesc::try_from(&buf[..size - 1]);
+        assert!(matches!(result, Err(Error::Truncated { needed, .. }) if needed == size));
+    }
+
     #[test]
     fn random_data() {
         let mut output = tempfile().unwrap();
@@ -274,9 +1837,276 @@ mod tests {
         let mut rng = SmallRng::seed_from_u64(42);
         let data = generate_test_data(data_size_count, &mut rng);
         let mut collector = Collector::new();
-        for buffer in data {
+        for buffer in data.iter() {
+            collector.add(buffer).unwrap();
+        }
+        let written = collector.press(&mut output).unwrap();
+        assert_eq!(written as u64, output.metadata().unwrap().len());
+
+        let reader = Reader::from_file(&output).unwrap();
+        assert_eq!(reader.shelves().count(), 5);
+        for shelf in reader.shelves() {
+            let mut expected: Vec<&[u8]> = data
+                .iter()
+                .filter(|buffer| buffer.len() == shelf.block_size())
+                .map(|buffer| buffer.as_slice())
+                .collect();
+            let mut blocks: Vec<Vec<u8>> = shelf.blocks().map(Cow::into_owned).collect();
+            expected.sort();
+            blocks.sort();
+            assert_eq!(blocks, expected);
+        }
+        for buffer in data.iter() {
+            assert!(reader.contains(buffer));
+        }
+        assert!(!reader.contains([0u8; 7]));
+        assert!(!reader.contains([0u8; 8]));
+    }
+
+    #[test]
+    fn dedup_on_add() {
+        let mut collector = Collector::new();
+        let first = collector.add(b"abcd").unwrap();
+        assert!(first.is_new());
+        assert!(collector.add(b"efgh").unwrap().is_new());
+        let again = collector.add(b"abcd").unwrap();
+        assert_eq!(again, Added::Duplicate(first.id()));
+        assert_eq!(collector.len(), 2);
+
+        let mut output = tempfile().unwrap();
+        collector.press(&mut output).unwrap();
+        let reader = Reader::from_file(&output).unwrap();
+        assert_eq!(reader.shelf(4).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn dedup_disabled() {
+        let mut collector = Collector::builder().dedup(false).build().unwrap();
+        let first = collector.add(b"abcd").unwrap();
+        let second = collector.add(b"abcd").unwrap();
+        assert!(second.is_new());
+        assert_ne!(first.id(), second.id());
+        assert_eq!(collector.len(), 2);
+    }
+
+    #[test]
+    fn builder_settings_in_header() {
+        let mut collector = Collector::builder()
+            .max_size(100)
+            .seed(7)
+            .dedup(false)
+            .alignment(64)
+            .build()
+            .unwrap();
+        assert!(matches!(collector.add([1u8; 101]), Err(Error::TooLarge(101))));
+        collector.add([1u8; 100]).unwrap();
+        collector.add([2u8; 3]).unwrap();
+        let mut output = tempfile().unwrap();
+        collector.press(&mut output).unwrap();
+
+        let reader = Reader::from_file(&output).unwrap();
+        let header = reader.header();
+        assert_eq!(header.max_size(), 100);
+        assert_eq!(header.seed(), 7);
+        assert!(!header.dedup());
+        assert_eq!(header.alignment(), 64);
+        for shelf in reader.shelves() {
+            assert_eq!(shelf.offset() % 64, 0);
+        }
+        assert!(reader.contains([1u8; 100]));
+        assert!(reader.contains([2u8; 3]));
+    }
+
+    #[test]
+    fn heap_shelf() {
+        let mut collector = Collector::builder()
+            .max_size(4096)
+            .heap_threshold(16)
+            .build()
+            .unwrap();
+        let mut rng = SmallRng::seed_from_u64(7);
+        let sizes: Vec<(usize, usize)> = (1..40).map(|size| (size * 50, 2)).collect();
+        let large = generate_test_data(sizes, &mut rng);
+        let small = generate_test_data(vec![(4, 3), (15, 2)], &mut rng);
+        for buffer in large.iter().chain(small.iter()) {
             collector.add(buffer).unwrap();
         }
-        collector.press(&mut output).unwrap(); 
+        assert!(!collector.add(&large[3]).unwrap().is_new());
+        let mut output = tempfile().unwrap();
+        let written = collector.press(&mut output).unwrap();
+        assert_eq!(written as u64, output.metadata().unwrap().len());
+
+        let reader = Reader::from_file(&output).unwrap();
+        assert_eq!(reader.header().blocklist_size(), 3);
+        let heap = reader.heap().unwrap();
+        assert_eq!(heap.kind(), RunKind::Heap);
+        assert_eq!(heap.len(), large.len());
+        assert!(reader.shelf(50).is_none());
+        for buffer in large.iter().chain(small.iter()) {
+            assert!(reader.contains(buffer));
+        }
+        for index in 0..heap.len() {
+            assert!(heap.get_checked(index).unwrap().is_some());
+        }
+        let mut blocks: Vec<Vec<u8>> = heap.blocks().map(Cow::into_owned).collect();
+        let mut expected: Vec<&[u8]> = large.iter().map(Vec::as_slice).collect();
+        blocks.sort();
+        expected.sort();
+        assert_eq!(blocks, expected);
+        assert!(!reader.contains([0u8; 100]));
+    }
+
+    #[test]
+    fn version_1_still_pressed_and_read() {
+        let mut collector = Collector::builder()
+            .version(1)
+            .max_size(256)
+            .heap_threshold(32)
+            .build()
+            .unwrap();
+        let mut rng = SmallRng::seed_from_u64(1);
+        let data = generate_test_data(vec![(3, 4), (31, 2), (40, 3), (200, 2)], &mut rng);
+        for buffer in data.iter() {
+            collector.add(buffer).unwrap();
+        }
+        let mut output = tempfile().unwrap();
+        let written = collector.press(&mut output).unwrap();
+        assert_eq!(written as u64, output.metadata().unwrap().len());
+
+        let reader = Reader::from_file(&output).unwrap();
+        assert_eq!(reader.header().version(), 1);
+        reader.verify().unwrap();
+        for buffer in data.iter() {
+            assert!(reader.contains(buffer));
+        }
+        assert!(Collector::builder().version(1).max_size(1 << 40).build().is_err());
+        assert!(Collector::builder().version(2).max_size(1 << 40).build().is_ok());
+    }
+
+    #[test]
+    fn legacy_version_1_layout_read() {
+        // What the baseline writer pressed from "one", "two", "three", "four", "two": the header
+        // and run table, the block data was never written
+        const PRESSED: [u8; 48] = [
+            0xbb, 0x33, 0xaa, 0x55, 0x01, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00,
+            0x00, 0x24, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x39, 0x00, 0x00, 0x00, 0x05, 0x00,
+            0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x41, 0x00, 0x00, 0x00,
+        ];
+        // What it pressed from an empty collector
+        const EMPTY: [u8; 12] = [0xbb, 0x33, 0xaa, 0x55, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+
+        let header = Header::from_buf(&PRESSED).unwrap();
+        assert_eq!((header.version(), header.blocklist_size()), (Header::LEGACY_VERSION, 3));
+        let descs: Vec<(usize, usize, usize)> = PRESSED[header.size()..]
+            .chunks(RunDesc::encoded_size(Header::LEGACY_VERSION))
+            .map(|buf| RunDesc::decode(buf, Header::LEGACY_VERSION).unwrap())
+            .map(|desc| (desc.block_size(), desc.count(), desc.offset()))
+            .collect();
+        // Offsets count from the end of the header, the first run would start right after the table
+        assert_eq!(descs, [(3, 3, 48), (4, 1, 69), (5, 1, 77)]);
+
+        let open = |bytes: &[u8]| {
+            let mut file = tempfile().unwrap();
+            file.write_all(bytes).unwrap();
+            Reader::from_file(&file)
+        };
+        let err = open(&PRESSED).unwrap_err();
+        assert!(err.to_string().contains("has no block data"), "{err}");
+        assert!(open(&PRESSED[..47]).is_err());
+
+        let reader = open(&EMPTY).unwrap();
+        assert_eq!(reader.header().version(), Header::LEGACY_VERSION);
+        assert_eq!(reader.shelves().count(), 0);
+        reader.verify().unwrap();
+        // Collectors built from the file get the current version, upgrading presses an empty file
+        let collector = CollectorBuilder::from_reader(&reader).unwrap().build().unwrap();
+        assert_eq!(collector.version, Header::VERSION);
+        let mut upgraded = Vec::new();
+        upgrade(&reader, &mut upgraded).unwrap();
+        let mut expected = Vec::new();
+        Collector::builder().dedup(false).build().unwrap().press(&mut expected).unwrap();
+        assert!(upgraded == expected);
+
+        // Lengths computed from the descriptor fields don't wrap around
+        let mut crafted = EMPTY.to_vec();
+        crafted[8] = 1;
+        for field in [u32::MAX, u32::MAX, 24] {
+            crafted.extend_from_slice(&field.to_le_bytes());
+        }
+        assert!(matches!(open(&crafted), Err(Error::Overflow(_))));
+    }
+
+    #[test]
+    fn parallel_press_matches() {
+        let mut rng = SmallRng::seed_from_u64(19);
+        let sizes: Vec<(usize, usize)> = (1..60).map(|size| (size, 40)).collect();
+        let data = generate_test_data(sizes, &mut rng);
+        for builder in [
+            Collector::builder().max_size(64),
+            Collector::builder().max_size(64).heap_threshold(30).alignment(64).perfect_hash(true).filter(0.01),
+            Collector::builder().max_size(64).version(1),
+        ] {
+            let mut collector = builder.build().unwrap();
+            for buffer in data.iter() {
+                collector.add(buffer).unwrap();
+            }
+            let mut expected = Vec::new();
+            collector.press(&mut expected).unwrap();
+            for threads in [1, 4] {
+                // Leftovers from an earlier, longer file are cut off
+                let mut output = tempfile().unwrap();
+                output.write_all(&vec![0xFF; expected.len() + 100]).unwrap();
+                let written = collector.press_parallel(&output, threads).unwrap();
+                assert_eq!(written, expected.len());
+                let mut pressed = Vec::new();
+                output.rewind().unwrap();
+                output.read_to_end(&mut pressed).unwrap();
+                assert!(pressed == expected);
+            }
+        }
+    }
+
+    #[test]
+    fn shuffled_input_presses_same_bytes() {
+        let mut rng = SmallRng::seed_from_u64(20);
+        let sizes: Vec<(usize, usize)> = (1..50).map(|size| (size, 30)).collect();
+        let mut data = generate_test_data(sizes, &mut rng);
+        data.extend_from_within(..200);
+        let press = |builder: CollectorBuilder, data: &[Vec<u8>]| {
+            let mut collector = builder.build().unwrap();
+            for buffer in data {
+                // Values derived from the key keep reinserts order independent
+                collector.insert(buffer, &buffer[..buffer.len() / 2]).unwrap();
+            }
+            let mut output = Vec::new();
+            collector.press(&mut output).unwrap();
+            output
+        };
+        for builder in [
+            Collector::builder().max_size(64),
+            Collector::builder().max_size(64).dedup(false),
+            Collector::builder().max_size(64).heap_threshold(20).alignment(32).perfect_hash(true).filter(0.001),
+        ] {
+            let expected = press(builder.clone(), &data);
+            for _ in 0..3 {
+                data.shuffle(&mut rng);
+                assert!(press(builder.clone(), &data) == expected, "{builder:?}");
+            }
+        }
+
+        // Pinned, so output that differs on another platform or after a code change fails here
+        let mut collector = Collector::builder().heap_threshold(8).perfect_hash(true).filter(0.01).build().unwrap();
+        for block in ["alpha", "beta", "gamma", "delta", "epsilon", "zeta", "eta", "theta", "iota", "kappa"] {
+            collector.insert(block, block.to_uppercase()).unwrap();
+        }
+        let mut output = Vec::new();
+        collector.press(&mut output).unwrap();
+        assert_eq!(hash::checksum(&output), 0x04C9_4ABA);
+    }
+
+    #[test]
+    fn builder_rejects_bad_settings() {
+        assert!(Collector::builder().alignment(3).build().is_err());
+        assert!(matches!(Collector::builder().version(9).build(), Err(Error::InvalidVersion)));
     }
 }
@@ -0,0 +1,658 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    process::ExitCode,
+};
+
+use rody::{Codec, Collector, CollectorBuilder, Error, Features, Layer, LayeredReader, Reader, Result, SpillingCollector};
+
+const USAGE: &str = "\
+Usage: rody <command> [options]
+
+Commands:
+  build [options] -o <output> [input...]
+      Collect blocks from the inputs (stdin if none) and press them
+        --delimiter line|length  Blocks are newline separated (default) or each
+                                 prefixed by its length as a little-endian u32
+        --max-size <bytes>       Largest accepted block
+        --seed <u32>             Block hash seed
+        --alignment <bytes>      Alignment of the bulk regions
+        --heap-threshold <bytes> Keep blocks at least this long on the heap
+        --no-dedup               Keep duplicate blocks
+        --perfect-hash           Index every shelf with a minimal perfect hash
+        --filter <rate>          Add a Bloom filter with this false positive
+                                 rate to every shelf
+        --compress lz4|zstd      Compress shelves, if built with the codec
+        --chunk-size <bytes>     Uncompressed size of a compressed chunk
+        --values                 Blocks are keys each followed by a value, on
+                                 the same line after a tab or as the next block
+        --format-version <n>     File format version to press
+        --appendable             Allow appending blocks to the file later
+        --memory-budget <bytes>  Spill sorted runs to temporary files once the
+                                 buffered blocks take about this much memory
+        --spill-dir <dir>        Directory for the temporary files
+        --threads <n>            Encode and write shelves on this many threads
+        -v, --verbose            Print the collector's shelves
+  append [--delimiter line|length] <file> [input...]
+      Collect blocks from the inputs (stdin if none) and append them to a file
+      built with --appendable, keys and values if the file holds values
+  info <file>
+      Print the header and the run table
+  dump [--base64] <file>
+      Print every block, hex encoded unless --base64 is given
+  verify <file>
+      Check all checksums
+  contains [--hex] <file> <block...>
+      Test whether each block is in the file, exits with 1 if any is missing
+  get [--hex] <file> <key...>
+      Print the value of each key, exits with 1 if any is missing
+  upgrade -o <output> <file>
+      Rewrite a file in the newest format version
+  merge -o <output> <file...>
+      Merge files hashed with the same seed into one, dropping duplicates
+  intersect -o <output> <file...>
+      Press the blocks found in every file
+  difference -o <output> <file...>
+      Press the blocks of the first file found in none of the others
+  diff -o <delta> <old> <new>
+      Write the changes turning one file into another
+  apply -o <output> <old> <delta>
+      Press the file a delta was made for from its old file
+  compact -o <output> <base> [--add <file> | --delete <file>]...
+      Flatten layers of additions and tombstones, given bottom up, onto a base
+";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match run(&args) {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("rody: {err}");
+            ExitCode::from(2)
+        }
+    }
+}
+
+fn run(args: &[String]) -> Result<ExitCode> {
+    let Some((command, args)) = args.split_first() else {
+        eprint!("{USAGE}");
+        return Ok(ExitCode::from(2));
+    };
+    match command.as_str() {
+        "build" => build(args),
+        "append" => append(args),
+        "info" => info(args),
+        "dump" => dump(args),
+        "verify" => verify(args),
+        "contains" => contains(args),
+        "get" => get(args),
+        "upgrade" => upgrade(args),
+        "merge" => merge(args),
+        "intersect" => set_operation(args, "intersect", rody::intersect),
+        "difference" => set_operation(args, "difference", rody::difference),
+        "diff" => diff(args),
+        "apply" => apply(args),
+        "compact" => compact(args),
+        "help" | "-h" | "--help" => {
+            print!("{USAGE}");
+            Ok(ExitCode::SUCCESS)
+        }
+        other => Err(format!("Unknown command '{other}', see 'rody help'").into()),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Delimiter {
+    Line,
+    Length,
+}
+
+fn build(args: &[String]) -> Result<ExitCode> {
+    let mut builder = CollectorBuilder::new();
+    let mut delimiter = Delimiter::Line;
+    let mut output = None;
+    let mut verbose = false;
+    let mut values = false;
+    let mut memory_budget = None;
+    let mut threads = 1;
+    let mut inputs = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" | "--output" => output = Some(value(&mut args, arg)?),
+            "--delimiter" => delimiter = parse_delimiter(&value(&mut args, arg)?)?,
+            "--max-size" => builder = builder.max_size(number(&mut args, arg)?),
+            "--seed" => builder = builder.seed(number(&mut args, arg)?),
+            "--alignment" => builder = builder.alignment(number(&mut args, arg)?),
+            "--heap-threshold" => builder = builder.heap_threshold(number(&mut args, arg)?),
+            "--no-dedup" => builder = builder.dedup(false),
+            "--perfect-hash" => builder = builder.perfect_hash(true),
+            "--filter" => builder = builder.filter(number(&mut args, arg)?),
+            "--compress" => {
+                builder = builder.compression(match value(&mut args, arg)?.as_str() {
+                    "lz4" => Codec::Lz4,
+                    "zstd" => Codec::Zstd,
+                    other => return Err(format!("Unknown codec '{other}'").into()),
+                })
+            }
+            "--chunk-size" => builder = builder.chunk_size(number(&mut args, arg)?),
+            "--values" => values = true,
+            "--format-version" => builder = builder.version(number(&mut args, arg)?),
+            "--appendable" => builder = builder.appendable(true),
+            "--memory-budget" => memory_budget = Some(number(&mut args, arg)?),
+            "--spill-dir" => builder = builder.spill_dir(value(&mut args, arg)?),
+            "--threads" => threads = number(&mut args, arg)?,
+            "-v" | "--verbose" => verbose = true,
+            _ => inputs.push(positional(arg)?),
+        }
+    }
+    let output = output.ok_or_else(|| Error::from("build needs an output file, pass -o <output>"))?;
+
+    if threads > 1 && memory_budget.is_some() {
+        return Err("--threads can't be combined with --memory-budget".into());
+    }
+    match memory_budget {
+        Some(budget) => {
+            let mut collector = builder.build_spilling(budget)?;
+            collect_inputs(&mut collector, &inputs, delimiter, values)?;
+            let mut writer = BufWriter::new(File::create(&output)?);
+            let written = collector.press(&mut writer)?;
+            writer.flush()?;
+            eprintln!("Pressed {} spilled runs into {output} ({written} bytes)", collector.spilled_runs());
+        }
+        None => {
+            let mut collector = builder.build()?;
+            collect_inputs(&mut collector, &inputs, delimiter, values)?;
+            let written = if threads > 1 {
+                collector.press_parallel(&File::create(&output)?, threads)?
+            } else {
+                let mut writer = BufWriter::new(File::create(&output)?);
+                let written = collector.press(&mut writer)?;
+                writer.flush()?;
+                written
+            };
+            if verbose {
+                eprintln!("{collector:#?}");
+            }
+            eprintln!("Pressed {} blocks into {output} ({written} bytes)", collector.len());
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn append(args: &[String]) -> Result<ExitCode> {
+    let mut delimiter = Delimiter::Line;
+    let mut files = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--delimiter" => delimiter = parse_delimiter(&value(&mut args, arg)?)?,
+            _ => files.push(positional(arg)?),
+        }
+    }
+    let Some((path, inputs)) = files.split_first() else {
+        return Err("append needs the file to append to".into());
+    };
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    // The blocks are collected with the settings the file was pressed with
+    let (mut collector, values) = {
+        let reader = Reader::from_file(&file)?;
+        let values = reader.header().optional_features().contains(Features::VALUES);
+        (CollectorBuilder::from_reader(&reader)?.build()?, values)
+    };
+    collect_inputs(&mut collector, inputs, delimiter, values)?;
+    let stats = collector.append(&mut file)?;
+    eprintln!(
+        "Appended {} blocks to {path} ({} bytes), skipped {} the file holds already",
+        stats.blocks, stats.written, stats.skipped
+    );
+    Ok(ExitCode::SUCCESS)
+}
+
+fn parse_delimiter(name: &str) -> Result<Delimiter> {
+    match name {
+        "line" => Ok(Delimiter::Line),
+        "length" => Ok(Delimiter::Length),
+        other => Err(format!("Unknown delimiter '{other}'").into()),
+    }
+}
+
+/// Where `collect` puts the blocks it reads
+trait Sink {
+    fn max_size(&self) -> usize;
+    fn add(&mut self, block: &[u8]) -> Result<()>;
+    fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<()>;
+}
+
+impl Sink for Collector {
+    fn max_size(&self) -> usize {
+        Collector::max_size(self)
+    }
+
+    fn add(&mut self, block: &[u8]) -> Result<()> {
+        Collector::add(self, block).map(drop)
+    }
+
+    fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        Collector::insert(self, key, value).map(drop)
+    }
+}
+
+impl Sink for SpillingCollector {
+    fn max_size(&self) -> usize {
+        SpillingCollector::max_size(self)
+    }
+
+    fn add(&mut self, block: &[u8]) -> Result<()> {
+        SpillingCollector::add(self, block)
+    }
+
+    fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        SpillingCollector::insert(self, key, value)
+    }
+}
+
+/// Collect from every input file, or stdin if there are none
+fn collect_inputs<S: Sink>(collector: &mut S, inputs: &[String], delimiter: Delimiter, values: bool) -> Result<()> {
+    if inputs.is_empty() {
+        return collect(collector, io::stdin().lock(), delimiter, values);
+    }
+    for input in inputs.iter() {
+        collect(collector, BufReader::new(File::open(input)?), delimiter, values)?;
+    }
+    Ok(())
+}
+
+/// Add every block read from `input` to the collector, or every key and value if `values` is set
+fn collect<S: Sink, R: BufRead>(collector: &mut S, mut input: R, delimiter: Delimiter, values: bool) -> Result<()> {
+    match delimiter {
+        Delimiter::Line => {
+            for line in input.split(b'\n') {
+                let mut line = line?;
+                if line.last() == Some(&b'\r') {
+                    line.pop();
+                }
+                if !values {
+                    collector.add(&line)?;
+                    continue;
+                }
+                let tab = line.iter().position(|byte| *byte == b'\t').unwrap_or(line.len());
+                let (key, value) = line.split_at(tab);
+                collector.insert(key, value.get(1..).unwrap_or_default())?;
+            }
+        }
+        Delimiter::Length => {
+            while let Some(block) = read_block(&mut input, collector.max_size())? {
+                if !values {
+                    collector.add(&block)?;
+                    continue;
+                }
+                let value = read_block(&mut input, usize::MAX)?.ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+                collector.insert(&block, &value)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Read a block prefixed by its length, at most `max_size` bytes, None on a clean end of input
+fn read_block<R: Read>(input: &mut R, max_size: usize) -> Result<Option<Vec<u8>>> {
+    let mut length = [0u8; 4];
+    if !read_prefix(input, &mut length)? {
+        return Ok(None);
+    }
+    let length = u32::from_le_bytes(length) as usize;
+    if length > max_size {
+        return Err(Error::TooLarge(length));
+    }
+    // The buffer grows with what the input holds rather than what the prefix claims
+    let mut block = Vec::new();
+    input.take(length as u64).read_to_end(&mut block)?;
+    if block.len() < length {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(Some(block))
+}
+
+/// Fill `prefix`, returning false on a clean end of input before its first byte
+fn read_prefix<R: Read>(input: &mut R, prefix: &mut [u8]) -> Result<bool> {
+    let mut filled = 0;
+    while filled < prefix.len() {
+        match input.read(&mut prefix[filled..])? {
+            0 if filled == 0 => return Ok(false),
+            0 => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+            read => filled += read,
+        }
+    }
+    Ok(true)
+}
+
+fn info(args: &[String]) -> Result<ExitCode> {
+    let reader = Reader::open(single_file(args)?)?;
+    println!("{:#?}", reader.header());
+    for (shelf, desc) in reader.shelves().zip(reader.runs()) {
+        println!("{shelf:?} {desc:?}");
+    }
+    let blocks: usize = reader.shelves().map(|shelf| shelf.len()).sum();
+    println!("{} shelves, {blocks} blocks", reader.runs().len());
+    Ok(ExitCode::SUCCESS)
+}
+
+fn dump(args: &[String]) -> Result<ExitCode> {
+    let mut encode: fn(&[u8]) -> String = hex;
+    let mut files = Vec::new();
+    for arg in args {
+        match arg.as_str() {
+            "--base64" => encode = base64,
+            "--hex" => encode = hex,
+            _ => files.push(positional(arg)?),
+        }
+    }
+    let reader = Reader::open(single_file(&files)?)?;
+    let mut stdout = BufWriter::new(io::stdout().lock());
+    for shelf in reader.shelves() {
+        for block in shelf.blocks() {
+            writeln!(stdout, "{}", encode(&block))?;
+        }
+    }
+    stdout.flush()?;
+    Ok(ExitCode::SUCCESS)
+}
+
+fn verify(args: &[String]) -> Result<ExitCode> {
+    let path = single_file(args)?;
+    match Reader::open(path).and_then(|reader| reader.verify()) {
+        Ok(()) => {
+            println!("{path}: ok");
+            Ok(ExitCode::SUCCESS)
+        }
+        Err(Error::IoError(err)) => Err(err.into()),
+        Err(err) => {
+            println!("{path}: {err}");
+            Ok(ExitCode::FAILURE)
+        }
+    }
+}
+
+fn contains(args: &[String]) -> Result<ExitCode> {
+    let mut decode_hex = false;
+    let mut positionals = Vec::new();
+    for arg in args {
+        match arg.as_str() {
+            "--hex" => decode_hex = true,
+            _ => positionals.push(positional(arg)?),
+        }
+    }
+    let Some((path, blocks)) = positionals.split_first() else {
+        return Err("contains needs a file and at least one block".into());
+    };
+    let reader = Reader::open(path)?;
+    let mut all_found = true;
+    for block in blocks {
+        let bytes = if decode_hex {
+            unhex(block)?
+        } else {
+            block.as_bytes().to_vec()
+        };
+        let found = reader.contains(&bytes);
+        all_found &= found;
+        println!("{block}: {}", if found { "found" } else { "missing" });
+    }
+    Ok(if all_found { ExitCode::SUCCESS } else { ExitCode::FAILURE })
+}
+
+fn get(args: &[String]) -> Result<ExitCode> {
+    let mut decode_hex = false;
+    let mut positionals = Vec::new();
+    for arg in args {
+        match arg.as_str() {
+            "--hex" => decode_hex = true,
+            _ => positionals.push(positional(arg)?),
+        }
+    }
+    let Some((path, keys)) = positionals.split_first() else {
+        return Err("get needs a file and at least one key".into());
+    };
+    let reader = Reader::open(path)?;
+    let mut all_found = true;
+    for key in keys {
+        let bytes = if decode_hex {
+            unhex(key)?
+        } else {
+            key.as_bytes().to_vec()
+        };
+        match reader.get(&bytes) {
+            Some(value) => println!("{key}: {}", String::from_utf8_lossy(value)),
+            None => {
+                all_found = false;
+                println!("{key}: missing");
+            }
+        }
+    }
+    Ok(if all_found { ExitCode::SUCCESS } else { ExitCode::FAILURE })
+}
+
+fn upgrade(args: &[String]) -> Result<ExitCode> {
+    let mut output = None;
+    let mut files = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" | "--output" => output = Some(value(&mut args, arg)?),
+            _ => files.push(positional(arg)?),
+        }
+    }
+    let output = output.ok_or_else(|| Error::from("upgrade needs an output file, pass -o <output>"))?;
+    let reader = Reader::open(single_file(&files)?)?;
+    let mut writer = BufWriter::new(File::create(&output)?);
+    let written = rody::upgrade(&reader, &mut writer)?;
+    writer.flush()?;
+    eprintln!(
+        "Upgraded version {} to {} into {output} ({written} bytes)",
+        reader.header().version(),
+        rody::Header::VERSION
+    );
+    Ok(ExitCode::SUCCESS)
+}
+
+fn diff(args: &[String]) -> Result<ExitCode> {
+    let (output, files) = output_and_files(args, "diff")?;
+    let [old, new] = files.as_slice() else {
+        return Err("diff needs the old and the new file".into());
+    };
+    let (old, new) = (Reader::open(old)?, Reader::open(new)?);
+    let mut writer = BufWriter::new(File::create(&output)?);
+    let stats = rody::diff(&old, &new, &mut writer)?;
+    writer.flush()?;
+    eprintln!("Wrote {} removed and {} added blocks into {output} ({} bytes)", stats.removed, stats.added, stats.written);
+    Ok(ExitCode::SUCCESS)
+}
+
+fn apply(args: &[String]) -> Result<ExitCode> {
+    let (output, files) = output_and_files(args, "apply")?;
+    let [old, delta] = files.as_slice() else {
+        return Err("apply needs the old file and the delta".into());
+    };
+    let (old, delta) = (Reader::open(old)?, rody::Delta::open(delta)?);
+    let mut writer = BufWriter::new(File::create(&output)?);
+    let written = rody::apply(&old, &delta, &mut writer)?;
+    writer.flush()?;
+    eprintln!("Applied {} removed and {} added blocks into {output} ({written} bytes)", delta.removed(), delta.added());
+    Ok(ExitCode::SUCCESS)
+}
+
+fn compact(args: &[String]) -> Result<ExitCode> {
+    let mut output = None;
+    let mut base = None;
+    let mut layers = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" | "--output" => output = Some(value(&mut args, arg)?),
+            "--add" => layers.push((Layer::Additions, value(&mut args, arg)?)),
+            "--delete" => layers.push((Layer::Tombstones, value(&mut args, arg)?)),
+            _ if base.is_none() => base = Some(positional(arg)?),
+            _ => return Err(format!("Unexpected argument '{arg}', layers go after --add or --delete").into()),
+        }
+    }
+    let output = output.ok_or_else(|| Error::from("compact needs an output file, pass -o <output>"))?;
+    let base = base.ok_or_else(|| Error::from("compact needs a base file"))?;
+    let mut layered = LayeredReader::new(Reader::open(base)?);
+    for (layer, file) in layers {
+        layered.push(layer, Reader::open(file)?)?;
+    }
+    let mut writer = BufWriter::new(File::create(&output)?);
+    let stats = layered.compact(&mut writer)?;
+    writer.flush()?;
+    eprintln!("Compacted {} layers into {output} ({} blocks, {} bytes)", layered.layers(), stats.blocks, stats.written);
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Parse the `-o <output> <file...>` arguments of `command`
+fn output_and_files(args: &[String], command: &str) -> Result<(String, Vec<String>)> {
+    let mut output = None;
+    let mut files = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" | "--output" => output = Some(value(&mut args, arg)?),
+            _ => files.push(positional(arg)?),
+        }
+    }
+    let output = output.ok_or_else(|| Error::from(format!("{command} needs an output file, pass -o <output>")))?;
+    if files.is_empty() {
+        return Err(format!("{command} needs at least one input file").into());
+    }
+    Ok((output, files))
+}
+
+fn merge(args: &[String]) -> Result<ExitCode> {
+    let (output, files) = output_and_files(args, "merge")?;
+    let readers = files.iter().map(Reader::open).collect::<Result<Vec<_>>>()?;
+    let mut writer = BufWriter::new(File::create(&output)?);
+    let stats = rody::merge(&readers, &mut writer)?;
+    writer.flush()?;
+    for (file, contributed) in files.iter().zip(stats.contributed.iter()) {
+        eprintln!("{file}: {contributed} new blocks");
+    }
+    eprintln!("Merged {} blocks into {output} ({} bytes)", stats.blocks, stats.written);
+    Ok(ExitCode::SUCCESS)
+}
+
+fn set_operation(args: &[String], command: &str, operation: fn(&[Reader]) -> Result<rody::SetBlocks<'_>>) -> Result<ExitCode> {
+    let (output, files) = output_and_files(args, command)?;
+    let readers = files.iter().map(Reader::open).collect::<Result<Vec<_>>>()?;
+    let mut writer = BufWriter::new(File::create(&output)?);
+    let stats = operation(&readers)?.press(&mut writer)?;
+    writer.flush()?;
+    eprintln!("Pressed {} blocks into {output} ({} bytes)", stats.blocks, stats.written);
+    Ok(ExitCode::SUCCESS)
+}
+
+fn value<'a>(args: &mut impl Iterator<Item = &'a String>, option: &str) -> Result<String> {
+    args.next()
+        .cloned()
+        .ok_or_else(|| format!("{option} needs a value").into())
+}
+
+fn number<'a, T: std::str::FromStr>(args: &mut impl Iterator<Item = &'a String>, option: &str) -> Result<T> {
+    let value = value(args, option)?;
+    value
+        .parse()
+        .map_err(|_| format!("{option} expects a number, got '{value}'").into())
+}
+
+fn positional(arg: &str) -> Result<String> {
+    if arg.starts_with('-') && arg != "-" {
+        return Err(format!("Unknown option '{arg}'").into());
+    }
+    Ok(arg.to_string())
+}
+
+fn single_file(args: &[String]) -> Result<&str> {
+    match args {
+        [path] => positional(path).map(|_| path.as_str()),
+        _ => Err("Expected exactly one file".into()),
+    }
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn unhex(text: &str) -> Result<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return Err(format!("Odd number of hex digits in '{text}'").into());
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|start| {
+            text.get(start..start + 2)
+                .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+                .ok_or_else(|| format!("Invalid hex '{text}'").into())
+        })
+        .collect()
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bits = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |bits, (index, byte)| bits | (*byte as u32) << (16 - 8 * index));
+        for index in 0..4 {
+            if index <= chunk.len() {
+                encoded.push(ALPHABET[(bits >> (18 - 6 * index) & 0x3F) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodings() {
+        assert_eq!(hex(&[0x00, 0xAB, 0x7F]), "00ab7f");
+        assert_eq!(unhex("00ab7f").unwrap(), vec![0x00, 0xAB, 0x7F]);
+        assert!(unhex("abc").is_err());
+        assert!(unhex("zz").is_err());
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+    }
+
+    #[test]
+    fn collect_delimiters() {
+        let mut collector = Collector::new();
+        collect(&mut collector, &b"one\r\ntwo\nthree\n"[..], Delimiter::Line, false).unwrap();
+        assert_eq!(collector.len(), 3);
+
+        let mut collector = Collector::new();
+        let mut input = Vec::new();
+        for block in [&b"abc"[..], b"", b"defgh"] {
+            input.extend_from_slice(&(block.len() as u32).to_le_bytes());
+            input.extend_from_slice(block);
+        }
+        collect(&mut collector, &input[..], Delimiter::Length, false).unwrap();
+        assert_eq!(collector.len(), 3);
+        assert!(collect(&mut collector, &input[..input.len() - 1], Delimiter::Length, false).is_err());
+
+        let mut collector = Collector::new();
+        collect(&mut collector, &b"one\t1\ntwo\n"[..], Delimiter::Line, true).unwrap();
+        assert_eq!(collector.len(), 2);
+        // Keys and values alternate, the last key is missing its value
+        assert!(collect(&mut Collector::new(), &input[..], Delimiter::Length, true).is_err());
+        // A length prefix over the max size is refused before reading the block
+        let huge = u32::MAX.to_le_bytes();
+        assert!(matches!(collect(&mut Collector::new(), &huge[..], Delimiter::Length, false), Err(Error::TooLarge(_))));
+    }
+}
@@ -0,0 +1,568 @@
+//! Merging sorted streams of blocks, and set operations on pressed files
+//!
+//! Shelves hold their blocks sorted by hash, then bytes, then value, so the
+//! matching shelves of several files merge like sorted runs. [`union`],
+//! [`intersect`] and [`difference`] stream every output shelf through a k-way
+//! merge and either hand out the resulting blocks or press them straight into
+//! a new file, without collecting the blocks first. [`merge`] presses a union.
+
+use std::{
+    borrow::Cow,
+    cmp::{Ordering, Reverse},
+    collections::{BTreeMap, BinaryHeap},
+    io::Write,
+    vec,
+};
+
+use crate::{spool::ShelfWriter, Collector, Error, Features, Reader, Result, RunKind, ShelfRef};
+
+/// What [`merge`] or [`SetBlocks::press`] wrote
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MergeStats {
+    /// Bytes written
+    pub written: usize,
+    /// Blocks in the pressed file
+    pub blocks: usize,
+    /// For every input in order, how many of the pressed blocks no earlier input held
+    pub contributed: Vec<usize>,
+}
+
+/// Merge pressed files into one, returning what was written
+///
+/// Duplicates are dropped unless an input was pressed without dedup, a key
+/// with values in several inputs keeps the value of the last of them. See
+/// [`union`] for the settings of the output.
+pub fn merge<W: Write>(inputs: &[Reader], writer: &mut W) -> Result<MergeStats> {
+    union(inputs)?.press(writer)
+}
+
+/// Blocks held by any of `inputs`
+///
+/// The inputs must share a hash seed. A file pressed from the result is in
+/// the newest format version and takes its settings from the inputs: the
+/// largest maximum block size and alignment, the lowest heap threshold, the
+/// lowest filter false positive rate, the first codec with the default chunk
+/// size, a perfect hash index and values if any input has them. Blocks routed
+/// differently under the new heap threshold move to the heap.
+pub fn union(inputs: &[Reader]) -> Result<SetBlocks<'_>> {
+    SetBlocks::new(inputs, SetOp::Union, Vec::new())
+}
+
+/// Blocks held by every one of `inputs`, see [`union`] for the requirements and settings
+pub fn intersect(inputs: &[Reader]) -> Result<SetBlocks<'_>> {
+    SetBlocks::new(inputs, SetOp::Intersection, Vec::new())
+}
+
+/// Blocks held by the first of `inputs` and none of the others, see [`union`] for the requirements and settings
+pub fn difference(inputs: &[Reader]) -> Result<SetBlocks<'_>> {
+    SetBlocks::new(inputs, SetOp::Difference, Vec::new())
+}
+
+/// Blocks of `inputs` stacked in order, where `tombstones` flags the inputs deleting the blocks they hold from the ones under them
+///
+/// The result is pressed with the settings of the inputs not flagged, see
+/// [`crate::LayeredReader`].
+pub(crate) fn overlay(inputs: &[Reader], tombstones: Vec<bool>) -> Result<SetBlocks<'_>> {
+    SetBlocks::new(inputs, SetOp::Overlay, tombstones)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum SetOp {
+    Union,
+    Intersection,
+    Difference,
+    Overlay,
+}
+
+impl SetOp {
+    /// Whether a block held by `holders`, sorted input indexes out of `inputs`, is in the result
+    fn keeps(self, holders: &[usize], inputs: usize, tombstones: &[bool]) -> bool {
+        match self {
+            SetOp::Union => true,
+            SetOp::Intersection => holders.len() == inputs,
+            SetOp::Difference => holders == [0],
+            SetOp::Overlay => holders.last().is_some_and(|top| !tombstones[*top]),
+        }
+    }
+}
+
+/// An input shelf merged into an output shelf, with its input and whether
+/// only some of its blocks belong on the output shelf
+type Source<'a> = (usize, ShelfRef<'a>, bool);
+
+/// An output shelf and the input shelves merged into it
+type ShelfSources<'a> = ((RunKind, usize), Vec<Source<'a>>);
+
+/// Result of a set operation on pressed files, see [`union`], [`intersect`] and [`difference`]
+///
+/// Iterating yields the data of every resulting block, shelf by shelf in run
+/// table order, borrowed from the inputs unless it comes from a compressed
+/// shelf. [`SetBlocks::press`] writes the blocks not yet yielded into a new file.
+pub struct SetBlocks<'a> {
+    op: SetOp,
+    inputs: usize,
+    // Whether each input holds tombstones, empty unless overlaying
+    tombstones: Vec<bool>,
+    // Empty collector holding the settings of the output
+    collector: Collector,
+    shelves: vec::IntoIter<ShelfSources<'a>>,
+    current: Option<ShelfMerge<'a>>,
+}
+
+/// Merge in progress of one output shelf
+struct ShelfMerge<'a> {
+    kind: RunKind,
+    block_size: usize,
+    // Input of every merge source
+    inputs_of: Vec<usize>,
+    merge: Merge<'a>,
+}
+
+impl<'a> SetBlocks<'a> {
+    fn new(inputs: &'a [Reader], op: SetOp, tombstones: Vec<bool>) -> Result<Self> {
+        if let Some(other) = inputs.iter().find(|reader| reader.seed() != inputs[0].seed()) {
+            return Err(format!("Inputs hashed with different seeds {:#x} and {:#x}", inputs[0].seed(), other.seed()).into());
+        }
+        let is_tombstone = |input: usize| tombstones.get(input).copied().unwrap_or(false);
+        let settings: Vec<&Reader> = inputs.iter().enumerate().filter(|(input, _)| !is_tombstone(*input)).map(|(_, reader)| reader).collect();
+        let mut collector = merged_collector(&settings)?;
+        // Telling which inputs hold a block needs equal blocks merged
+        if op != SetOp::Union {
+            collector.dedup = true;
+        }
+        let route = Route(collector.heap.as_ref().map(|heap| heap.block_size));
+        let mut sources: BTreeMap<(RunKind, usize), Vec<Source>> = BTreeMap::new();
+        for (input, reader) in inputs.iter().enumerate().filter(|(input, _)| !is_tombstone(*input)) {
+            for shelf in reader.shelves() {
+                // The heap threshold is the lowest of these inputs, their heaps all go to the heap
+                let key = match route.0 {
+                    Some(threshold) if shelf.kind() == RunKind::Heap => (RunKind::Heap, threshold),
+                    _ => route.shelf_for(shelf.block_size()),
+                };
+                sources.entry(key).or_default().push((input, shelf, false));
+            }
+        }
+        // Tombstones only matter on shelves with blocks to delete
+        for (input, reader) in inputs.iter().enumerate().filter(|(input, _)| is_tombstone(*input)) {
+            for shelf in reader.shelves() {
+                let spread = shelf.kind() == RunKind::Heap && route.0.is_none_or(|threshold| shelf.block_size() < threshold);
+                for (key, key_sources) in sources.iter_mut() {
+                    let matches = if spread {
+                        key.0 == RunKind::Heap || key.1 >= shelf.block_size()
+                    } else {
+                        route.shelf_for(shelf.block_size()) == *key
+                    };
+                    if matches {
+                        key_sources.push((input, shelf, spread));
+                    }
+                }
+            }
+        }
+        // Merges take later sources as newer
+        for key_sources in sources.values_mut() {
+            key_sources.sort_by_key(|(input, _, _)| *input);
+        }
+        Ok(Self {
+            op,
+            inputs: inputs.len(),
+            tombstones: (0..inputs.len()).map(is_tombstone).collect(),
+            collector,
+            shelves: sources.into_iter().collect::<Vec<_>>().into_iter(),
+            current: None,
+        })
+    }
+
+    fn start(&self, ((kind, block_size), sources): ShelfSources<'a>) -> Result<ShelfMerge<'a>> {
+        let route = Route(self.collector.heap.as_ref().map(|heap| heap.block_size));
+        let inputs_of: Vec<usize> = sources.iter().map(|(input, _, _)| *input).collect();
+        let tombstones = inputs_of.iter().map(|input| self.tombstones[*input]).collect();
+        let streams = sources
+            .into_iter()
+            .map(|(_, shelf, spread)| -> Box<dyn Iterator<Item = Result<Entry>> + 'a> {
+                if !spread {
+                    return shelf_entries(shelf);
+                }
+                let belongs = move |entry: &Result<Entry>| entry.as_ref().map_or(true, |entry| route.shelf_for(entry.data.len()) == (kind, block_size));
+                Box::new(shelf_entries(shelf).filter(belongs))
+            })
+            .collect();
+        Ok(ShelfMerge {
+            kind,
+            block_size,
+            inputs_of,
+            merge: Merge::new(streams, self.collector.dedup)?.with_tombstones(tombstones),
+        })
+    }
+
+    /// Next resulting block of `shelf` and the inputs holding it, in order
+    fn kept(&self, shelf: &mut ShelfMerge<'a>) -> Result<Option<(Vec<usize>, Entry<'a>)>> {
+        while let Some(merged) = shelf.merge.next().transpose()? {
+            let mut holders: Vec<usize> = merged.sources.iter().map(|source| shelf.inputs_of[*source]).collect();
+            holders.sort_unstable();
+            holders.dedup();
+            if self.op.keeps(&holders, self.inputs, &self.tombstones) {
+                return Ok(Some((holders, merged.entry)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Press the blocks not yielded yet into a new file, returning what was written
+    pub fn press<W: Write>(mut self, writer: &mut W) -> Result<MergeStats> {
+        let mut contributed = vec![0; self.inputs];
+        let mut blocks = 0;
+        let mut shelves = Vec::new();
+        loop {
+            let mut merge = match self.current.take() {
+                Some(merge) => merge,
+                None => match self.shelves.next() {
+                    Some(sources) => self.start(sources)?,
+                    None => break,
+                },
+            };
+            let mut shelf = ShelfWriter::new(&self.collector, merge.kind, merge.block_size, None)?;
+            while let Some((holders, entry)) = self.kept(&mut merge)? {
+                shelf.push(entry.hash, &entry.data, entry.value.as_deref().unwrap_or_default())?;
+                contributed[holders[0]] += 1;
+                blocks += 1;
+            }
+            shelves.extend(shelf.finish()?);
+        }
+        let written = self.collector.write_shelves(&shelves, writer)?;
+        Ok(MergeStats {
+            written,
+            blocks,
+            contributed,
+        })
+    }
+
+    fn next_block(&mut self) -> Result<Option<Cow<'a, [u8]>>> {
+        loop {
+            let mut merge = match self.current.take() {
+                Some(merge) => merge,
+                None => match self.shelves.next() {
+                    Some(sources) => self.start(sources)?,
+                    None => return Ok(None),
+                },
+            };
+            if let Some((_, entry)) = self.kept(&mut merge)? {
+                self.current = Some(merge);
+                return Ok(Some(entry.data));
+            }
+        }
+    }
+}
+
+impl<'a> Iterator for SetBlocks<'a> {
+    type Item = Result<Cow<'a, [u8]>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_block().transpose()
+    }
+}
+
+/// Empty collector holding the settings the merge of `inputs` is pressed with
+fn merged_collector(inputs: &[&Reader]) -> Result<Collector> {
+    let mut builder = Collector::builder();
+    let Some(first) = inputs.first() else {
+        return builder.build();
+    };
+    let max_size = inputs.iter().map(|reader| reader.header().max_size()).max().unwrap_or_default();
+    let has_feature = |feature| inputs.iter().any(|reader| reader.header().optional_features().contains(feature));
+    builder = builder
+        .seed(first.seed())
+        .max_size(usize::try_from(max_size).map_err(|_| Error::Overflow(max_size))?)
+        .dedup(inputs.iter().all(|reader| reader.header().dedup()))
+        .alignment(inputs.iter().map(|reader| reader.header().alignment()).max().unwrap_or(1))
+        .perfect_hash(has_feature(Features::PERFECT_HASH));
+    let shelves = || inputs.iter().flat_map(|reader| reader.shelves());
+    if let Some(threshold) = inputs.iter().filter_map(|reader| reader.heap()).map(|heap| heap.block_size()).min() {
+        builder = builder.heap_threshold(threshold);
+    }
+    if let Some(rate) = shelves().filter_map(|shelf| shelf.filter_rate()).min_by(f64::total_cmp) {
+        builder = builder.filter(rate);
+    }
+    if let Some(codec) = shelves().find_map(|shelf| shelf.codec()) {
+        builder = builder.compression(codec);
+    }
+    let mut collector = builder.build()?;
+    collector.values = has_feature(Features::VALUES);
+    Ok(collector)
+}
+
+/// Routes blocks to shelves by length under a heap threshold
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Route(pub(crate) Option<usize>);
+
+impl Route {
+    /// Kind and block size of the shelf holding blocks of `length` bytes
+    pub(crate) fn shelf_for(self, length: usize) -> (RunKind, usize) {
+        match self.0 {
+            Some(threshold) if length >= threshold => (RunKind::Heap, threshold),
+            _ => (RunKind::Fixed, length),
+        }
+    }
+}
+
+/// Entries of a shelf in on-disk order, with their values if the file has any
+pub(crate) fn shelf_entries(shelf: ShelfRef) -> Box<dyn Iterator<Item = Result<Entry>> + '_> {
+    let mut blocks = shelf.blocks();
+    Box::new((0..shelf.len()).map(move |index| {
+        let (hash, data) = blocks.entry(index).ok_or_else(|| shelf.outside_run(index))?;
+        Ok(Entry {
+            hash,
+            data,
+            value: shelf.value(index).map(Cow::Borrowed),
+        })
+    }))
+}
+
+/// A block taking part in a merge, borrowed where its source allows
+pub(crate) struct Entry<'a> {
+    pub hash: u32,
+    pub data: Cow<'a, [u8]>,
+    // None for a block added without a value
+    pub value: Option<Cow<'a, [u8]>>,
+}
+
+/// A merged block and the sources holding it, in ascending order
+pub(crate) struct Merged<'a> {
+    pub entry: Entry<'a>,
+    pub sources: Vec<usize>,
+}
+
+/// Next entry of a merge source
+struct Head<'a> {
+    entry: Entry<'a>,
+    source: usize,
+}
+
+impl Head<'_> {
+    fn key(&self) -> (u32, &[u8], Option<&[u8]>, usize) {
+        (self.entry.hash, &self.entry.data, self.entry.value.as_deref(), self.source)
+    }
+}
+
+impl PartialEq for Head<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for Head<'_> {}
+
+impl PartialOrd for Head<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Head<'_> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+/// K-way merge of sources sorted in on-disk order, later sources are newer
+///
+/// With dedup, equal blocks are merged into one carrying the value from the
+/// newest source that had one, as re-inserting into a [`Collector`] would.
+/// Values never come from tombstone sources or the sources under them.
+pub(crate) struct Merge<'a> {
+    sources: Vec<Box<dyn Iterator<Item = Result<Entry<'a>>> + 'a>>,
+    heads: BinaryHeap<Reverse<Head<'a>>>,
+    dedup: bool,
+    // Whether each source holds tombstones, empty if none do
+    tombstones: Vec<bool>,
+}
+
+impl<'a> Merge<'a> {
+    pub fn new(sources: Vec<Box<dyn Iterator<Item = Result<Entry<'a>>> + 'a>>, dedup: bool) -> Result<Self> {
+        let mut merge = Self {
+            heads: BinaryHeap::with_capacity(sources.len()),
+            sources,
+            dedup,
+            tombstones: Vec::new(),
+        };
+        for source in 0..merge.sources.len() {
+            merge.advance(source)?;
+        }
+        Ok(merge)
+    }
+
+    /// Flag the sources holding tombstones
+    pub fn with_tombstones(mut self, tombstones: Vec<bool>) -> Self {
+        self.tombstones = tombstones;
+        self
+    }
+
+    fn is_tombstone(&self, source: usize) -> bool {
+        self.tombstones.get(source).copied().unwrap_or(false)
+    }
+
+    /// Queue the next entry of `source`
+    fn advance(&mut self, source: usize) -> Result<()> {
+        if let Some(entry) = self.sources[source].next().transpose()? {
+            self.heads.push(Reverse(Head { entry, source }));
+        }
+        Ok(())
+    }
+
+    fn merged(&mut self) -> Result<Option<Merged<'a>>> {
+        let Some(Reverse(head)) = self.heads.pop() else {
+            return Ok(None);
+        };
+        self.advance(head.source)?;
+        let (mut sources, mut entry) = (vec![head.source], head.entry);
+        if !self.dedup {
+            return Ok(Some(Merged { entry, sources }));
+        }
+        let mut value_source = (entry.value.is_some() && !self.is_tombstone(head.source)).then_some(head.source);
+        let mut newest_tombstone = self.is_tombstone(head.source).then_some(head.source);
+        while let Some(Reverse(next)) = self.heads.peek() {
+            if (next.entry.hash, &next.entry.data) != (entry.hash, &entry.data) {
+                break;
+            }
+            let Some(Reverse(next)) = self.heads.pop() else { break };
+            self.advance(next.source)?;
+            sources.push(next.source);
+            if self.is_tombstone(next.source) {
+                newest_tombstone = newest_tombstone.max(Some(next.source));
+            } else if next.entry.value.is_some() && value_source < Some(next.source) {
+                entry.value = next.entry.value;
+                value_source = Some(next.source);
+            }
+        }
+        if value_source.is_none() || value_source < newest_tombstone {
+            entry.value = None;
+        }
+        sources.sort_unstable();
+        Ok(Some(Merged { entry, sources }))
+    }
+}
+
+impl<'a> Iterator for Merge<'a> {
+    type Item = Result<Merged<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.merged().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Codec, CollectorBuilder};
+    use tempfile::tempfile;
+
+    fn press(builder: CollectorBuilder, pairs: &[(String, String)]) -> Reader {
+        let mut collector = builder.build().unwrap();
+        for (key, value) in pairs {
+            collector.insert(key, value).unwrap();
+        }
+        let mut output = tempfile().unwrap();
+        collector.press(&mut output).unwrap();
+        Reader::from_file(&output).unwrap()
+    }
+
+    #[test]
+    fn merge_shards() {
+        let pairs = |range: std::ops::Range<usize>, tag: &str| -> Vec<(String, String)> {
+            range.map(|n| ("k".repeat(n % 30 + 1) + &n.to_string(), format!("{tag}{n}"))).collect()
+        };
+        let shards = [pairs(0..400, "a"), pairs(300..700, "b"), pairs(650..800, "c")];
+        let mut builders = vec![Collector::builder().heap_threshold(20).perfect_hash(true)];
+        builders.extend(
+            [Codec::Lz4, Codec::Zstd]
+                .into_iter()
+                .filter(Codec::is_available)
+                .map(|codec| Collector::builder().heap_threshold(20).compression(codec)),
+        );
+        for builder in builders {
+            let inputs: Vec<Reader> = shards.iter().map(|shard| press(builder.clone(), shard)).collect();
+            let mut merged = Vec::new();
+            let stats = merge(&inputs, &mut merged).unwrap();
+            assert_eq!(stats.blocks, 800);
+            assert_eq!(stats.contributed, vec![400, 300, 100]);
+
+            // Same bytes as collecting everything, later shards overriding values
+            let mut expected = Vec::new();
+            let mut collector = builder.build().unwrap();
+            for (key, value) in shards.iter().flatten() {
+                collector.insert(key, value).unwrap();
+            }
+            collector.press(&mut expected).unwrap();
+            assert_eq!(stats.written, expected.len());
+            assert!(merged == expected);
+        }
+    }
+
+    #[test]
+    fn set_operations() {
+        let key = |n: usize| "s".repeat(n % 30 + 1) + &n.to_string();
+        let pairs = |range: std::ops::Range<usize>| -> Vec<(String, String)> { range.map(|n| (key(n), n.to_string())).collect() };
+        let builder = Collector::builder().heap_threshold(20);
+        let inputs = [press(builder.clone(), &pairs(0..300)), press(builder.clone(), &pairs(200..500)), press(builder, &pairs(250..600))];
+        let keys = |blocks: SetBlocks| -> Vec<String> {
+            let mut keys: Vec<String> = blocks.map(|block| String::from_utf8(block.unwrap().into_owned()).unwrap()).collect();
+            keys.sort();
+            keys
+        };
+        let expected = |range: std::ops::Range<usize>| -> Vec<String> {
+            let mut keys: Vec<String> = range.map(key).collect();
+            keys.sort();
+            keys
+        };
+        assert_eq!(keys(union(&inputs).unwrap()), expected(0..600));
+        assert_eq!(keys(intersect(&inputs[..2]).unwrap()), expected(200..300));
+        assert_eq!(keys(intersect(&inputs).unwrap()), expected(250..300));
+        assert_eq!(keys(difference(&inputs).unwrap()), expected(0..200));
+        assert_eq!(keys(difference(&inputs[1..]).unwrap()), expected(200..250));
+        // Uncompressed shelves lend their blocks
+        assert!(intersect(&inputs).unwrap().all(|block| matches!(block, Ok(Cow::Borrowed(_)))));
+
+        let mut output = tempfile().unwrap();
+        let stats = intersect(&inputs[1..]).unwrap().press(&mut output).unwrap();
+        assert_eq!((stats.blocks, stats.contributed), (250, vec![250, 0]));
+        let reader = Reader::from_file(&output).unwrap();
+        reader.verify().unwrap();
+        for n in 0..600 {
+            let value = n.to_string();
+            assert_eq!(reader.get(key(n)), (250..500).contains(&n).then_some(value.as_bytes()));
+        }
+
+        // Pressing after iterating keeps the blocks not yielded yet
+        let mut blocks = difference(&inputs).unwrap();
+        let taken = blocks.by_ref().take(150).count();
+        let stats = blocks.press(&mut Vec::new()).unwrap();
+        assert_eq!(taken + stats.blocks, 200);
+    }
+
+    #[test]
+    fn merge_mixed_settings() {
+        let pairs: Vec<(String, String)> = (0..200).map(|n| ("x".repeat(n % 25) + &n.to_string(), n.to_string())).collect();
+        let inputs = [
+            press(Collector::builder().dedup(false).alignment(8), &pairs[..100]),
+            press(Collector::builder().heap_threshold(10).filter(0.05), &pairs[50..]),
+        ];
+        let mut output = tempfile().unwrap();
+        let stats = merge(&inputs, &mut output).unwrap();
+        // Without dedup in the first input, the overlap stays duplicated
+        assert_eq!(stats.blocks, 250);
+        assert_eq!(stats.contributed, vec![100, 150]);
+        let reader = Reader::from_file(&output).unwrap();
+        reader.verify().unwrap();
+        assert!(!reader.header().dedup());
+        assert_eq!(reader.header().alignment(), 8);
+        assert_eq!(reader.heap().unwrap().block_size(), 10);
+        assert_eq!(reader.heap().unwrap().filter_rate(), Some(0.05));
+        for (key, _) in pairs.iter() {
+            assert!(reader.contains(key));
+        }
+
+        let other_seed = press(Collector::builder().seed(1), &pairs);
+        assert!(merge(&[other_seed, press(Collector::builder(), &pairs)], &mut Vec::new()).is_err());
+        let stats = merge(&[], &mut Vec::new()).unwrap();
+        assert_eq!((stats.blocks, stats.contributed.len()), (0, 0));
+    }
+}
@@ -0,0 +1,257 @@
+//! Minimal perfect hash index over the block hashes of a shelf
+//!
+//! Built in the style of PTHash: keys are spread over buckets of about
+//! [`BUCKET_SIZE`] keys, and each bucket gets a pilot, the first value that
+//! sends all of its keys to free positions when mixed into their hashes.
+//! There are slightly more positions than keys so the last buckets still find
+//! room quickly. Positions past the key count are then remapped onto the
+//! free ones below it, which makes the final function minimal.
+//!
+//! The keys are the distinct stored hashes of a shelf, the slot of each holds
+//! the index of the first entry with that hash. Entries sharing a hash are
+//! adjacent since shelves are sorted by hash, so a lookup reads one slot and
+//! compares bytes from there.
+//!
+//! The encoding is four little-endian `u64` fields, seed, key count, position
+//! count and bucket count, then a `u32` pilot per bucket, a `u64` per position
+//! past the key count holding its remapped position, and a `u64` entry index
+//! per key.
+
+use std::{cmp::Reverse, mem::size_of};
+
+use crate::{hash, Decoder, Error, Result};
+
+/// Average number of keys per bucket
+const BUCKET_SIZE: usize = 4;
+/// Pilot values tried for a bucket before giving up on the seed
+const MAX_PILOT: u32 = 1 << 20;
+/// Seeds tried before giving up on building the index
+const MAX_ATTEMPTS: u64 = 16;
+
+const FIELDS_SIZE: usize = 4 * size_of::<u64>();
+const PILOT_SIZE: usize = size_of::<u32>();
+const WORD_SIZE: usize = size_of::<u64>();
+
+/// Map `hash` onto `0..range` without a division
+fn reduce(hash: u64, range: usize) -> usize {
+    (((hash >> 32) * range as u64) >> 32) as usize
+}
+
+/// The parameters lookups and construction share
+#[derive(Clone, Copy, Debug)]
+struct Params {
+    seed: u64,
+    keys: usize,
+    positions: usize,
+    buckets: usize,
+}
+
+impl Params {
+    fn new(seed: u64, keys: usize) -> Self {
+        Self {
+            seed,
+            keys,
+            // About one percent of slack, the pilot search for the last buckets needs it
+            positions: keys + keys / 100 + 1,
+            buckets: keys / BUCKET_SIZE + 1,
+        }
+    }
+
+    fn key_hash(&self, key: u32) -> u64 {
+        hash::mix(u64::from(key) ^ self.seed)
+    }
+
+    fn bucket(&self, key_hash: u64) -> usize {
+        reduce(key_hash, self.buckets)
+    }
+
+    fn position(&self, key_hash: u64, pilot: u32) -> usize {
+        (hash::mix(key_hash ^ hash::mix(u64::from(pilot).wrapping_add(self.seed))) % self.positions as u64) as usize
+    }
+}
+
+/// Build the index for `hashes`, the stored hashes of a shelf in entry order
+///
+/// Returns the encoded index, or None for an empty shelf.
+pub(crate) fn build(hashes: &[u32]) -> Result<Option<Vec<u8>>> {
+    // Distinct hashes and the index of the first entry holding each
+    let mut keys: Vec<(u32, u64)> = Vec::new();
+    for (index, hash) in hashes.iter().enumerate() {
+        if keys.last().map(|(last, _)| last) != Some(hash) {
+            keys.push((*hash, index as u64));
+        }
+    }
+    if keys.is_empty() {
+        return Ok(None);
+    }
+    for attempt in 0..MAX_ATTEMPTS {
+        let params = Params::new(hash::mix(attempt), keys.len());
+        if let Some(pilots) = search_pilots(&params, &keys) {
+            return Ok(Some(encode(&params, &keys, &pilots)));
+        }
+    }
+    Err(format!("Couldn't build a perfect hash for {} keys", keys.len()).into())
+}
+
+/// Find a pilot for every bucket, largest buckets first, None if some bucket has none
+fn search_pilots(params: &Params, keys: &[(u32, u64)]) -> Option<Vec<u32>> {
+    let mut hashed: Vec<(usize, u64)> = keys
+        .iter()
+        .map(|(key, _)| {
+            let hash = params.key_hash(*key);
+            (params.bucket(hash), hash)
+        })
+        .collect();
+    hashed.sort_unstable();
+    let mut starts = vec![0; params.buckets + 1];
+    for (bucket, _) in hashed.iter() {
+        starts[bucket + 1] += 1;
+    }
+    for bucket in 0..params.buckets {
+        starts[bucket + 1] += starts[bucket];
+    }
+    let mut order: Vec<usize> = (0..params.buckets).collect();
+    order.sort_by_key(|bucket| Reverse(starts[bucket + 1] - starts[*bucket]));
+
+    let mut taken = vec![false; params.positions];
+    let mut pilots = vec![0; params.buckets];
+    let mut positions = Vec::with_capacity(BUCKET_SIZE);
+    for bucket in order {
+        let members = &hashed[starts[bucket]..starts[bucket + 1]];
+        if members.is_empty() {
+            break;
+        }
+        let pilot = (0..MAX_PILOT).find(|pilot| {
+            positions.clear();
+            for (_, hash) in members {
+                let position = params.position(*hash, *pilot);
+                if taken[position] || positions.contains(&position) {
+                    return false;
+                }
+                positions.push(position);
+            }
+            true
+        })?;
+        for position in positions.iter() {
+            taken[*position] = true;
+        }
+        pilots[bucket] = pilot;
+    }
+    Some(pilots)
+}
+
+fn encode(params: &Params, keys: &[(u32, u64)], pilots: &[u32]) -> Vec<u8> {
+    let mut slots = vec![u64::MAX; params.positions];
+    for (key, index) in keys {
+        let hash = params.key_hash(*key);
+        slots[params.position(hash, pilots[params.bucket(hash)])] = *index;
+    }
+    // Move the keys placed past the end onto the holes below it
+    let mut remap = vec![0u64; params.positions - params.keys];
+    let mut holes = (0..params.keys).filter(|position| slots[*position] == u64::MAX).collect::<Vec<_>>().into_iter();
+    for position in params.keys..params.positions {
+        if slots[position] != u64::MAX {
+            let hole = holes.next().expect("as many holes as keys past the end");
+            slots[hole] = slots[position];
+            remap[position - params.keys] = hole as u64;
+        }
+    }
+
+    let size = FIELDS_SIZE + pilots.len() * PILOT_SIZE + (remap.len() + params.keys) * WORD_SIZE;
+    let mut payload = Vec::with_capacity(size);
+    for field in [params.seed, params.keys as u64, params.positions as u64, params.buckets as u64] {
+        payload.extend_from_slice(&field.to_le_bytes());
+    }
+    for pilot in pilots {
+        payload.extend_from_slice(&pilot.to_le_bytes());
+    }
+    for word in remap.iter().chain(&slots[..params.keys]) {
+        payload.extend_from_slice(&word.to_le_bytes());
+    }
+    debug_assert_eq!(payload.len(), size);
+    payload
+}
+
+/// Borrowed view of an encoded index
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct PerfectHash<'a> {
+    params: Params,
+    pilots: &'a [u8],
+    remap: &'a [u8],
+    slots: &'a [u8],
+}
+
+impl<'a> PerfectHash<'a> {
+    pub fn new(payload: &'a [u8]) -> Result<Self> {
+        let mut decoder = Decoder::new(payload);
+        let seed = decoder.u64()?;
+        let mut field = || -> Result<usize> {
+            let value = decoder.u64()?;
+            usize::try_from(value).map_err(|_| Error::Overflow(value))
+        };
+        let (keys, positions, buckets) = (field()?, field()?, field()?);
+        // Every key has a slot in the payload, which bounds the other sizes too
+        if keys > payload.len() / WORD_SIZE {
+            return Err(Error::Truncated {
+                needed: keys.saturating_mul(WORD_SIZE),
+                available: payload.len(),
+            });
+        }
+        let params = Params::new(seed, keys);
+        if (positions, buckets) != (params.positions, params.buckets) {
+            return Err(format!("Perfect hash for {keys} keys has {positions} positions and {buckets} buckets").into());
+        }
+        let mut decoder = Decoder::new(payload.get(FIELDS_SIZE..).unwrap_or_default());
+        Ok(Self {
+            params,
+            pilots: decoder.take(buckets * PILOT_SIZE)?,
+            remap: decoder.take((positions - keys) * WORD_SIZE)?,
+            slots: decoder.take(keys * WORD_SIZE)?,
+        })
+    }
+
+    /// Index of the first entry that may hold `key`, the caller compares the stored hash
+    pub fn lookup(&self, key: u32) -> Option<usize> {
+        let hash = self.params.key_hash(key);
+        let bucket = self.params.bucket(hash);
+        let pilot = read_u32(self.pilots, bucket)?;
+        let mut position = self.params.position(hash, pilot);
+        if position >= self.params.keys {
+            position = usize::try_from(read_u64(self.remap, position - self.params.keys)?).ok()?;
+        }
+        usize::try_from(read_u64(self.slots, position)?).ok()
+    }
+}
+
+fn read_u32(buf: &[u8], index: usize) -> Option<u32> {
+    let start = index.checked_mul(PILOT_SIZE)?;
+    Decoder::new(buf.get(start..)?).u32().ok()
+}
+
+fn read_u64(buf: &[u8], index: usize) -> Option<u64> {
+    let start = index.checked_mul(WORD_SIZE)?;
+    Decoder::new(buf.get(start..)?).u64().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::SmallRng, Rng, SeedableRng};
+
+    #[test]
+    fn every_key_finds_its_entry() {
+        let mut rng = SmallRng::seed_from_u64(7);
+        let mut hashes: Vec<u32> = (0..5000).map(|_| rng.gen()).collect();
+        // Entries sharing a hash
+        hashes.extend_from_slice(&[17, 17, 17]);
+        hashes.sort_unstable();
+        let payload = build(&hashes).unwrap().unwrap();
+        let index = PerfectHash::new(&payload).unwrap();
+        for (position, hash) in hashes.iter().enumerate() {
+            let first = hashes.partition_point(|other| other < hash);
+            assert_eq!(index.lookup(*hash), Some(first), "entry {position}");
+        }
+        assert!(build(&[]).unwrap().is_none());
+        assert!(PerfectHash::new(&payload[..payload.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,91 @@
+//! Helpers for [`crate::Collector::press_parallel`]
+
+use std::{
+    fs::File,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    thread,
+};
+
+use crate::Result;
+
+/// Apply `f` to every item on up to `threads` scoped threads, returning the results in item order
+///
+/// Threads take the next unclaimed item until none are left, so a few large
+/// items don't hold up the rest. The first error stops every thread.
+pub(crate) fn map<T, R, F>(items: &[T], threads: usize, f: F) -> Result<Vec<R>>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> Result<R> + Sync,
+{
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    let work = || -> Result<Vec<(usize, R)>> {
+        let mut done = Vec::new();
+        while !failed.load(Ordering::Relaxed) {
+            let index = next.fetch_add(1, Ordering::Relaxed);
+            let Some(item) = items.get(index) else { break };
+            match f(item) {
+                Ok(result) => done.push((index, result)),
+                Err(err) => {
+                    failed.store(true, Ordering::Relaxed);
+                    return Err(err);
+                }
+            }
+        }
+        Ok(done)
+    };
+    let threads = threads.clamp(1, items.len().max(1));
+    let outcomes: Vec<Result<Vec<(usize, R)>>> = thread::scope(|scope| {
+        let handles: Vec<_> = (0..threads).map(|_| scope.spawn(work)).collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic)))
+            .collect()
+    });
+    let mut results = Vec::with_capacity(items.len());
+    for outcome in outcomes {
+        results.extend(outcome?);
+    }
+    results.sort_unstable_by_key(|(index, _)| *index);
+    Ok(results.into_iter().map(|(_, result)| result).collect())
+}
+
+/// Write all of `buf` at `offset` in `file`, leaving the file position alone
+#[cfg(unix)]
+pub(crate) fn write_all_at(file: &File, buf: &[u8], offset: usize) -> Result<()> {
+    use std::os::unix::fs::FileExt;
+    Ok(file.write_all_at(buf, offset as u64)?)
+}
+
+/// Write all of `buf` at `offset` in `file`
+#[cfg(windows)]
+pub(crate) fn write_all_at(file: &File, mut buf: &[u8], mut offset: usize) -> Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_write(buf, offset as u64)? {
+            0 => return Err(std::io::Error::from(std::io::ErrorKind::WriteZero).into()),
+            written => {
+                buf = &buf[written..];
+                offset += written;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn map_keeps_item_order() {
+        let items: Vec<usize> = (0..100).collect();
+        for threads in [0, 1, 3, 200] {
+            let doubled = map(&items, threads, |item| Ok(item * 2)).unwrap();
+            assert_eq!(doubled, items.iter().map(|item| item * 2).collect::<Vec<_>>());
+        }
+        let result = map(&items, 4, |item| if *item == 50 { Err("fifty".into()) } else { Ok(()) });
+        assert!(result.is_err());
+    }
+}
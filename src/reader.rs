@@ -0,0 +1,985 @@
+use memmap::Mmap;
+use std::{borrow::Cow, fmt::Debug, fs::File, ops::Range, path::Path};
+
+use crate::{append::Footer, compress::ChunkIndex, extension::ValueTable, filter::{self, Filter}, hash, mph::PerfectHash, Block, Codec, Error, Extensions, Features, Header, HeapEntry, Result, RunDesc, RunKind, RunTable};
+
+/// Read-only view of a pressed file
+///
+/// The header and every run descriptor are validated when the reader is
+/// created, blocks are handed out as slices borrowed straight from the map.
+/// The runs of an appendable file are those of the run table its footer
+/// points to, see [`crate::Collector::append`].
+pub struct Reader {
+    map: Mmap,
+    header: Header,
+    runs: Vec<RunDesc>,
+    // Chunk index of every run, None for runs that aren't compressed
+    chunk_indexes: Vec<Option<ChunkIndex>>,
+}
+
+impl Debug for Reader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Reader")
+            .field("header", &self.header)
+            .field("shelves", &self.runs.len())
+            .finish()
+    }
+}
+
+impl Reader {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = File::open(path)?;
+        Self::from_file(&file)
+    }
+
+    pub fn from_file(file: &File) -> Result<Self> {
+        // The map is never written through, modifying the file underneath it is on the caller
+        let map = unsafe { Mmap::map(file) }?;
+        Self::from_map(map)
+    }
+
+    pub fn from_map(map: Mmap) -> Result<Self> {
+        let mut header = Header::from_map(&map)?;
+        Self::check_table(&map, &header)?;
+        let runs = match Self::footer(&map, &header)? {
+            Some(footer) => {
+                // Appended shelves may need codecs the header doesn't list
+                header.required_features = header.required_features.union(footer.required_features);
+                let runs = RunTable::appended(&map, &header, footer.table_offset, footer.runs).collect::<Result<Vec<_>>>()?;
+                // The table follows the runs it lists, the footer of a fresh file follows them all
+                let data_end = if footer.table_offset > header.size() { footer.table_offset } else { map.len() - Footer::SIZE };
+                if let Some(desc) = runs.last().filter(|desc| desc.end() > data_end as u64) {
+                    return Err(format!("Run at {} runs into the run table or footer at {data_end}", desc.offset()).into());
+                }
+                runs
+            }
+            None => RunTable::new(&map, &header).collect::<Result<Vec<_>>>()?,
+        };
+        let mut reader = Self {
+            map,
+            header,
+            runs,
+            chunk_indexes: Vec::new(),
+        };
+        // Compressed shelves can't be read at all without their chunk index
+        let chunk_indexes = reader
+            .runs
+            .iter()
+            .enumerate()
+            .map(|(run, desc)| reader.chunk_index(run, desc))
+            .collect::<Result<Vec<_>>>()?;
+        reader.chunk_indexes = chunk_indexes;
+        Ok(reader)
+    }
+
+    /// Check every checksum in the file, reporting the first corrupt region
+    ///
+    /// The header and run table are checked on open as well, the bulk and
+    /// extension regions only here since that means reading the whole file.
+    pub fn verify(&self) -> Result<()> {
+        let header = Header::from_map(&self.map)?;
+        Self::check_table(&self.map, &header)?;
+        Self::footer(&self.map, &header)?;
+        for (run, desc) in self.runs.iter().enumerate() {
+            let shelf = self.shelf_ref(run);
+            if hash::checksum(shelf.bulk) != desc.checksum() {
+                return Err(Error::BulkChecksum {
+                    run,
+                    block_size: desc.block_size(),
+                });
+            }
+            if hash::checksum(shelf.extension_region) != desc.extension_checksum() {
+                return Err(Error::ExtensionChecksum {
+                    run,
+                    block_size: desc.block_size(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn check_table(buf: &[u8], header: &Header) -> Result<()> {
+        let range = header.table_range()?;
+        let table = buf.get(range.clone()).ok_or(Error::Truncated {
+            needed: range.end,
+            available: buf.len(),
+        })?;
+        if hash::checksum(table) != header.table_checksum() {
+            return Err(Error::RunTableChecksum);
+        }
+        Ok(())
+    }
+
+    /// Footer of an appendable file, checked along with the run table it points to
+    fn footer(buf: &[u8], header: &Header) -> Result<Option<Footer>> {
+        if !header.required_features().contains(Features::APPENDABLE) {
+            return Ok(None);
+        }
+        Footer::from_end(buf, header).map(Some)
+    }
+
+    /// Checksum of the header and the latest run table, which hold the checksums of everything else
+    pub(crate) fn head_checksum(&self) -> u32 {
+        // Checked to fit in the map on open
+        let end = self.header.table_range().map_or(0, |range| range.end);
+        if !self.header.required_features().contains(Features::APPENDABLE) {
+            return hash::checksum(&self.map[..end]);
+        }
+        // The footer holds the latest run table's checksum
+        let footer = &self.map[self.map.len() - Footer::SIZE..];
+        hash::checksum(&[&self.map[..end], footer].concat())
+    }
+
+    /// Size of the whole file
+    pub(crate) fn file_size(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// The validated run table
+    pub fn runs(&self) -> &[RunDesc] {
+        &self.runs
+    }
+
+    /// Seed the block hashes in this file were computed with
+    pub fn seed(&self) -> u32 {
+        self.header.seed()
+    }
+
+    /// Iterate over the shelves in run table order, fixed shelves by ascending block size then the heap
+    pub fn shelves(&self) -> impl Iterator<Item = ShelfRef<'_>> {
+        (0..self.runs.len()).map(|run| self.shelf_ref(run))
+    }
+
+    /// Fixed shelf holding blocks of exactly `block_size` bytes, if there is one
+    ///
+    /// Appendable files may hold one per append, this is the first.
+    pub fn shelf(&self, block_size: usize) -> Option<ShelfRef<'_>> {
+        self.runs
+            .iter()
+            .position(|desc| desc.kind() == RunKind::Fixed && desc.block_size() == block_size)
+            .map(|run| self.shelf_ref(run))
+    }
+
+    /// Shelf holding the variable-length blocks, if the file has one
+    ///
+    /// Appendable files may hold one per append, this is the first.
+    pub fn heap(&self) -> Option<ShelfRef<'_>> {
+        self.runs.iter().position(|desc| desc.kind() == RunKind::Heap).map(|run| self.shelf_ref(run))
+    }
+
+    /// Whether `block` is one of the pressed blocks
+    pub fn contains<T: AsRef<[u8]>>(&self, block: T) -> bool {
+        self.find(block).is_some()
+    }
+
+    /// Index of `block` within the first shelf for its length holding it
+    pub fn find<T: AsRef<[u8]>>(&self, block: T) -> Option<usize> {
+        let block = block.as_ref();
+        self.shelves_for(block.len()).find_map(|shelf| shelf.find(block))
+    }
+
+    /// Value inserted along with `key`, see [`crate::Collector::insert`]
+    ///
+    /// Keys added without a value have an empty one, None means the key is
+    /// missing or the file holds no values.
+    pub fn get<T: AsRef<[u8]>>(&self, key: T) -> Option<&[u8]> {
+        let key = key.as_ref();
+        let (shelf, index) = self.shelves_for(key.len()).find_map(|shelf| Some((shelf, shelf.find(key)?)))?;
+        shelf.value(index)
+    }
+
+    /// Shelves a block of `length` bytes may be on: the fixed shelves of that size, then the heaps
+    fn shelves_for(&self, length: usize) -> impl Iterator<Item = ShelfRef<'_>> {
+        let runs = || self.runs.iter().enumerate();
+        let fixed = runs().filter(move |(_, desc)| desc.kind() == RunKind::Fixed && desc.block_size() == length);
+        let heaps = runs().filter(move |(_, desc)| desc.kind() == RunKind::Heap && length >= desc.block_size());
+        fixed.chain(heaps).map(|(run, _)| self.shelf_ref(run))
+    }
+
+    /// Bulk and extension regions of a run
+    fn regions<'a>(&'a self, desc: &RunDesc) -> (&'a [u8], &'a [u8]) {
+        // Bounds were checked by RunDesc::validate on open
+        let start = desc.offset();
+        let bulk = &self.map[start..start + desc.bulk_size()];
+        let start = desc.extension_offset();
+        (bulk, &self.map[start..start + desc.extension_size()])
+    }
+
+    /// Chunk index of compressed run `run`, None for runs that aren't
+    ///
+    /// The index decides how much decompressing a chunk allocates, so the
+    /// extension region holding it is checked against its checksum first.
+    fn chunk_index(&self, run: usize, desc: &RunDesc) -> Result<Option<ChunkIndex>> {
+        if !desc.is_compressed() {
+            return Ok(None);
+        }
+        let (_, extension_region) = self.regions(desc);
+        if hash::checksum(extension_region) != desc.extension_checksum() {
+            return Err(Error::ExtensionChecksum {
+                run,
+                block_size: desc.block_size(),
+            });
+        }
+        for record in Extensions::new(extension_region) {
+            let (tag, payload) = record?;
+            if tag == Extensions::CHUNKS {
+                let index = ChunkIndex::new(payload, desc, &self.header)?;
+                if !index.codec().is_available() {
+                    return Err(index.codec().unavailable());
+                }
+                return Ok(Some(index));
+            }
+        }
+        Err(format!("Compressed run for {} bytes has no chunk index", desc.block_size()).into())
+    }
+
+    fn shelf_ref(&self, run: usize) -> ShelfRef<'_> {
+        let desc = &self.runs[run];
+        let (bulk, extension_region) = self.regions(desc);
+        // Built on open
+        let chunks = self.chunk_indexes[run].as_ref();
+        let (mut values, mut index, mut filter) = (None, None, None);
+        // Unknown and malformed records are skipped, verify reports corrupt ones
+        for (tag, payload) in Extensions::new(extension_region).map_while(Result::ok) {
+            match tag {
+                Extensions::VALUES => values = ValueTable::new(payload, desc.count()),
+                Extensions::PERFECT_HASH => index = PerfectHash::new(payload).ok(),
+                Extensions::FILTER => filter = Filter::new(payload).ok(),
+                _ => {}
+            }
+        }
+        ShelfRef {
+            desc,
+            bulk,
+            extension_region,
+            values,
+            index,
+            filter,
+            chunks,
+            seed: self.seed(),
+            version: self.header.version(),
+        }
+    }
+}
+
+/// Borrowed view of a single shelf inside a pressed file
+#[derive(Clone, Copy)]
+pub struct ShelfRef<'a> {
+    desc: &'a RunDesc,
+    bulk: &'a [u8],
+    extension_region: &'a [u8],
+    values: Option<ValueTable<'a>>,
+    index: Option<PerfectHash<'a>>,
+    filter: Option<Filter<'a>>,
+    // Chunk index of a compressed shelf
+    chunks: Option<&'a ChunkIndex>,
+    seed: u32,
+    version: u32,
+}
+
+impl Debug for ShelfRef<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ShelfRef")
+            .field("kind", &self.kind())
+            .field("block_size", &self.block_size())
+            .field("blocks", &self.len())
+            .field("codec", &self.codec())
+            .finish()
+    }
+}
+
+impl<'a> ShelfRef<'a> {
+    pub fn kind(&self) -> RunKind {
+        self.desc.kind()
+    }
+
+    /// Length of every block for fixed shelves, the shortest block length for the heap
+    pub fn block_size(&self) -> usize {
+        self.desc.block_size()
+    }
+
+    pub fn len(&self) -> usize {
+        self.desc.count()
+    }
+
+    /// Offset of the shelf's bulk region within the file
+    pub fn offset(&self) -> usize {
+        self.desc.offset()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Codec the shelf is compressed with, if it is
+    pub fn codec(&self) -> Option<Codec> {
+        self.chunks.map(|chunks| chunks.codec())
+    }
+
+    /// Entries in every chunk but possibly the last, if the shelf is compressed
+    pub(crate) fn chunk_entries(&self) -> Option<usize> {
+        self.chunks.map(|chunks| chunks.chunk_entries())
+    }
+
+    /// Data of the block at `index`, without its hash
+    ///
+    /// Borrowed from the map, unless the shelf is compressed and the chunk
+    /// holding the block had to be decompressed.
+    pub fn get(&self, index: usize) -> Option<Cow<'a, [u8]>> {
+        match self.chunks {
+            None => self.entries().get(index).map(Cow::Borrowed),
+            Some(chunks) => {
+                let chunk = self.decompress(index / chunks.chunk_entries())?;
+                let data = chunk.entries().get(index % chunks.chunk_entries())?;
+                Some(Cow::Owned(data.to_vec()))
+            }
+        }
+    }
+
+    /// Hash stored alongside the block at `index`
+    pub fn hash(&self, index: usize) -> Option<u32> {
+        match self.chunks {
+            None => self.entries().hash(index),
+            Some(chunks) => {
+                let chunk = self.decompress(index / chunks.chunk_entries())?;
+                chunk.entries().hash(index % chunks.chunk_entries())
+            }
+        }
+    }
+
+    /// Like [`ShelfRef::get`], but recompute the block hash and compare it with the stored one
+    pub fn get_checked(&self, index: usize) -> Result<Option<Cow<'a, [u8]>>> {
+        let (Some(data), Some(stored)) = (self.get(index), self.hash(index)) else {
+            return Ok(None);
+        };
+        if stored != hash::hash(self.seed, &data) {
+            return Err(Error::HashMismatch {
+                block_size: self.block_size(),
+                index,
+            });
+        }
+        Ok(Some(data))
+    }
+
+    /// Error for entry `index` of the shelf missing from its run
+    pub(crate) fn outside_run(&self, index: usize) -> Error {
+        Error::OutsideRun {
+            kind: self.kind(),
+            block_size: self.block_size(),
+            index,
+        }
+    }
+
+    /// Index of `block` in this shelf
+    ///
+    /// Entries are sorted by hash, so this is a binary search for the hash
+    /// followed by a byte comparison across the entries sharing it. Shelves
+    /// with a perfect hash index read the first entry off it instead, and
+    /// shelves with a filter rule out most missing blocks before either.
+    /// Compressed shelves pick a chunk by the hash of its first entry and
+    /// only decompress that one, and the next if the hash runs over.
+    pub fn find(&self, block: &[u8]) -> Option<usize> {
+        let fits = match self.kind() {
+            RunKind::Fixed => block.len() == self.block_size(),
+            RunKind::Heap => block.len() >= self.block_size(),
+        };
+        if !fits {
+            return None;
+        }
+        let target = hash::hash(self.seed, block);
+        if let Some(filter) = self.filter {
+            if !filter.may_contain(filter::key(target, filter.seed(), block)) {
+                return None;
+            }
+        }
+        let Some(chunks) = self.chunks else {
+            let entries = self.entries();
+            let first = match self.index {
+                Some(index) => index.lookup(target)?,
+                None => entries.first_with_hash(target)?,
+            };
+            return entries.find_from(first, target, block);
+        };
+        let per_chunk = chunks.chunk_entries();
+        let (mut chunk, mut first) = match self.index {
+            Some(index) => {
+                let first = index.lookup(target)?;
+                (first / per_chunk, Some(first % per_chunk))
+            }
+            None => {
+                // The last chunk starting below the hash may hold its first entries
+                (chunks.chunks_below(target).saturating_sub(1), None)
+            }
+        };
+        loop {
+            // A chunk starting past the hash can't hold it, unless the index pointed there
+            if first.is_none() && chunks.chunk(chunk)?.first_hash > target {
+                return None;
+            }
+            let decompressed = self.decompress(chunk)?;
+            let entries = decompressed.entries();
+            let start = match first.take() {
+                Some(first) => first,
+                None => entries.first_with_hash(target)?,
+            };
+            if let Some(found) = entries.find_from(start, target, block) {
+                return Some(chunk * per_chunk + found);
+            }
+            // Entries sharing the hash may continue in the next chunk
+            if entries.hash(entries.count.checked_sub(1)?)? > target {
+                return None;
+            }
+            chunk += 1;
+        }
+    }
+
+    pub fn contains(&self, block: &[u8]) -> bool {
+        self.find(block).is_some()
+    }
+
+    /// False positive rate of the shelf's filter, if it has one
+    pub fn filter_rate(&self) -> Option<f64> {
+        self.filter.map(|filter| filter.rate())
+    }
+
+    /// Value of the block at `index` in a key/value file
+    pub fn value(&self, index: usize) -> Option<&'a [u8]> {
+        self.values?.get(index)
+    }
+
+    /// Records of the shelf's extension region, empty before version 3
+    pub fn extensions(&self) -> Extensions<'a> {
+        Extensions::new(self.extension_region)
+    }
+
+    /// Payload of the first extension record tagged `tag`
+    pub fn extension(&self, tag: u32) -> Result<Option<&'a [u8]>> {
+        for record in self.extensions() {
+            let (record_tag, payload) = record?;
+            if record_tag == tag {
+                return Ok(Some(payload));
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn blocks(&self) -> Blocks<'a> {
+        Blocks {
+            shelf: *self,
+            indexes: 0..self.len(),
+            chunk: None,
+        }
+    }
+
+    /// Entries of an uncompressed shelf
+    fn entries(&self) -> Entries<'a> {
+        Entries {
+            bulk: self.bulk,
+            kind: self.kind(),
+            block_size: self.block_size(),
+            count: self.len(),
+            version: self.version,
+        }
+    }
+
+    /// Decompress chunk `chunk` of a compressed shelf
+    fn decompress(&self, chunk: usize) -> Option<Chunk> {
+        let chunks = self.chunks?;
+        let first = chunk.checked_mul(chunks.chunk_entries())?;
+        Some(Chunk {
+            data: chunks.decompress(self.bulk, chunk).ok()?,
+            kind: self.kind(),
+            block_size: self.block_size(),
+            count: self.len().checked_sub(first)?.min(chunks.chunk_entries()),
+            version: self.version,
+        })
+    }
+}
+
+/// A decompressed chunk, laid out like an uncompressed bulk region
+struct Chunk {
+    data: Vec<u8>,
+    kind: RunKind,
+    block_size: usize,
+    count: usize,
+    version: u32,
+}
+
+impl Chunk {
+    fn entries(&self) -> Entries<'_> {
+        Entries {
+            bulk: &self.data,
+            kind: self.kind,
+            block_size: self.block_size,
+            count: self.count,
+            version: self.version,
+        }
+    }
+}
+
+/// Entries of an uncompressed bulk region, of a whole shelf or a decompressed chunk
+#[derive(Clone, Copy)]
+struct Entries<'b> {
+    bulk: &'b [u8],
+    kind: RunKind,
+    block_size: usize,
+    count: usize,
+    version: u32,
+}
+
+impl<'b> Entries<'b> {
+    fn get(&self, index: usize) -> Option<&'b [u8]> {
+        match self.kind {
+            RunKind::Fixed => self.entry(index).map(|entry| &entry[Block::HASH_SIZE..]),
+            RunKind::Heap => {
+                let entry = self.heap_entry(index)?;
+                let data = self.bulk.get(self.count.checked_mul(HeapEntry::encoded_size(self.version))?..)?;
+                let start = usize::try_from(entry.offset).ok()?;
+                let length = usize::try_from(entry.length).ok()?;
+                data.get(start..start.checked_add(length)?)
+            }
+        }
+    }
+
+    fn hash(&self, index: usize) -> Option<u32> {
+        match self.kind {
+            RunKind::Fixed => self.entry(index).and_then(Block::read_hash),
+            RunKind::Heap => self.heap_entry(index).map(|entry| entry.hash),
+        }
+    }
+
+    /// Binary search for the first entry whose hash is not below `target`
+    fn first_with_hash(&self, target: u32) -> Option<usize> {
+        let (mut low, mut high) = (0, self.count);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if self.hash(mid)? < target {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        Some(low)
+    }
+
+    /// Index of `block` among the entries from `first` on that share its hash `target`
+    fn find_from(&self, first: usize, target: u32, block: &[u8]) -> Option<usize> {
+        (first..self.count)
+            .take_while(|index| self.hash(*index) == Some(target))
+            .find(|index| self.get(*index) == Some(block))
+    }
+
+    /// Fixed shelf entry, the hash followed by the block data
+    fn entry(&self, index: usize) -> Option<&'b [u8]> {
+        if index >= self.count {
+            return None;
+        }
+        let stride = Block::HASH_SIZE.checked_add(self.block_size)?;
+        let start = index.checked_mul(stride)?;
+        self.bulk.get(start..start.checked_add(stride)?)
+    }
+
+    fn heap_entry(&self, index: usize) -> Option<HeapEntry> {
+        if index >= self.count {
+            return None;
+        }
+        let start = index.checked_mul(HeapEntry::encoded_size(self.version))?;
+        HeapEntry::from_buf(self.bulk.get(start..)?, self.version).ok()
+    }
+}
+
+/// Iterator over the block data of a shelf
+pub struct Blocks<'a> {
+    shelf: ShelfRef<'a>,
+    indexes: Range<usize>,
+    // Last chunk decompressed for a compressed shelf, blocks come in order
+    chunk: Option<(usize, Chunk)>,
+}
+
+impl<'a> Blocks<'a> {
+    /// Stored hash and data of the block at `index`, None if its entry is corrupt
+    ///
+    /// Reading blocks in order decompresses every chunk once.
+    pub(crate) fn entry(&mut self, index: usize) -> Option<(u32, Cow<'a, [u8]>)> {
+        let Some(chunks) = self.shelf.chunks else {
+            let entries = self.shelf.entries();
+            return Some((entries.hash(index)?, Cow::Borrowed(entries.get(index)?)));
+        };
+        let number = index / chunks.chunk_entries();
+        if !matches!(self.chunk, Some((cached, _)) if cached == number) {
+            self.chunk = Some((number, self.shelf.decompress(number)?));
+        }
+        let (_, chunk) = self.chunk.as_ref()?;
+        let entries = chunk.entries();
+        let index = index % chunks.chunk_entries();
+        Some((entries.hash(index)?, Cow::Owned(entries.get(index)?.to_vec())))
+    }
+}
+
+impl<'a> Iterator for Blocks<'a> {
+    type Item = Cow<'a, [u8]>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let index = self.indexes.next()?;
+        self.entry(index).map(|(_, data)| data)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.indexes.size_hint()
+    }
+}
+
+impl ExactSizeIterator for Blocks<'_> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Codec, Collector, Error, Features};
+    use std::{io::Write, os::unix::fs::FileExt};
+    use tempfile::tempfile;
+
+    #[test]
+    fn open_empty_press() {
+        let mut output = tempfile().unwrap();
+        Collector::new().press(&mut output).unwrap();
+        let reader = Reader::from_file(&output).unwrap();
+        assert_eq!(reader.header().blocklist_size(), 0);
+        assert_eq!(reader.shelves().count(), 0);
+        assert!(reader.shelf(4).is_none());
+    }
+
+    #[test]
+    fn stored_hashes_are_checked() {
+        let mut collector = Collector::new();
+        collector.add(b"first").unwrap();
+        collector.add(b"other").unwrap();
+        let mut output = tempfile().unwrap();
+        collector.press(&mut output).unwrap();
+
+        let reader = Reader::from_file(&output).unwrap();
+        let shelf = reader.shelf(5).unwrap();
+        assert_eq!(shelf.hash(0), Some(hash::hash(reader.seed(), b"first")));
+        assert_eq!(shelf.get_checked(1).unwrap().as_deref(), Some(&b"other"[..]));
+        assert_eq!(shelf.get_checked(2).unwrap(), None);
+        drop(reader);
+
+        // Flip a data byte of the second block behind the reader's back
+        let offset = output.metadata().unwrap().len() - 1;
+        output.write_all_at(b"X", offset).unwrap();
+        let reader = Reader::from_file(&output).unwrap();
+        let result = reader.shelf(5).unwrap().get_checked(1);
+        assert!(matches!(result, Err(Error::HashMismatch { block_size: 5, index: 1 })));
+    }
+
+    #[test]
+    fn find_in_sorted_shelf() {
+        let mut collector = Collector::new();
+        let blocks: Vec<[u8; 4]> = (0u32..200).map(|value| value.to_le_bytes()).collect();
+        for block in blocks.iter().rev() {
+            collector.add(block).unwrap();
+        }
+        let mut output = tempfile().unwrap();
+        collector.press(&mut output).unwrap();
+
+        let reader = Reader::from_file(&output).unwrap();
+        let shelf = reader.shelf(4).unwrap();
+        let hashes: Vec<u32> = (0..shelf.len()).map(|index| shelf.hash(index).unwrap()).collect();
+        assert!(hashes.windows(2).all(|pair| pair[0] <= pair[1]));
+        for block in blocks.iter() {
+            let index = reader.find(block).unwrap();
+            assert_eq!(shelf.get(index).as_deref(), Some(&block[..]));
+        }
+        assert!(!reader.contains(500u32.to_le_bytes()));
+        assert!(!reader.contains(b"abc"));
+    }
+
+    #[test]
+    fn reject_bad_magic() {
+        let mut output = tempfile().unwrap();
+        output.write_all(&[0u8; 64]).unwrap();
+        let result = Reader::from_file(&output);
+        assert!(matches!(result, Err(Error::BadMagic)));
+    }
+
+    #[test]
+    fn reject_short_file() {
+        let mut output = tempfile().unwrap();
+        output.write_all(&[0xBB, 0x33, 0xAA, 0x55, 1, 0]).unwrap();
+        let result = Reader::from_file(&output);
+        assert!(matches!(result, Err(Error::Truncated { needed: 8, available: 6 })));
+    }
+
+    #[test]
+    fn reject_truncated_run_table() {
+        let mut output = tempfile().unwrap();
+        Header::new(3).write_out(&mut output).unwrap();
+        let desc = RunDesc { kind: 0, block_size: 4, count: 0, offset: 0, length: 0, checksum: 0, ext_length: 0, ext_checksum: 0 };
+        desc.write_out(&mut output, Header::VERSION).unwrap();
+        assert!(matches!(Reader::from_file(&output), Err(Error::Truncated { .. })));
+    }
+
+    fn corrupt_at(offset: u64) -> Result<()> {
+        let mut collector = Collector::new();
+        for block in [&b"abc"[..], b"defg", b"hijkl", b"mnopq"] {
+            collector.add(block).unwrap();
+        }
+        let mut output = tempfile().unwrap();
+        collector.press(&mut output).unwrap();
+        let mut byte = [0u8];
+        output.read_exact_at(&mut byte, offset).unwrap();
+        output.write_all_at(&[byte[0] ^ 0x10], offset).unwrap();
+        Reader::from_file(&output)?.verify()
+    }
+
+    #[test]
+    fn verify_reports_corrupt_region() {
+        let table = Header::encoded_size(Header::VERSION) as u64;
+        let desc_size = RunDesc::encoded_size(Header::VERSION) as u64;
+        let bulk = table + 3 * desc_size;
+        // Seed field of the header
+        assert!(matches!(corrupt_at(16), Err(Error::HeaderChecksum)));
+        // Count of the second run
+        assert!(matches!(corrupt_at(table + desc_size + 12), Err(Error::RunTableChecksum)));
+        // Data of the first block in the third run, after two runs of 7 and 8 bytes
+        let result = corrupt_at(bulk + 7 + 8 + 4);
+        assert!(matches!(result, Err(Error::BulkChecksum { run: 2, block_size: 5 })));
+    }
+
+    #[test]
+    fn key_values() {
+        let mut collector = Collector::builder().heap_threshold(6).build().unwrap();
+        collector.insert(b"one", b"1").unwrap();
+        collector.insert(b"two", b"2").unwrap();
+        collector.insert(b"a longer key", b"on the heap").unwrap();
+        collector.add(b"bare").unwrap();
+        assert!(!collector.insert(b"two", b"zwei").unwrap().is_new());
+        let mut output = tempfile().unwrap();
+        collector.press(&mut output).unwrap();
+
+        let reader = Reader::from_file(&output).unwrap();
+        reader.verify().unwrap();
+        assert!(reader.header().optional_features().contains(Features::VALUES));
+        assert_eq!(reader.get(b"one"), Some(&b"1"[..]));
+        assert_eq!(reader.get(b"two"), Some(&b"zwei"[..]));
+        assert_eq!(reader.get(b"a longer key"), Some(&b"on the heap"[..]));
+        assert_eq!(reader.get(b"bare"), Some(&b""[..]));
+        assert_eq!(reader.get(b"six"), None);
+
+        // Sets have no values
+        let mut output = tempfile().unwrap();
+        let mut collector = Collector::new();
+        collector.add(b"one").unwrap();
+        collector.press(&mut output).unwrap();
+        assert_eq!(Reader::from_file(&output).unwrap().get(b"one"), None);
+        let mut collector = Collector::builder().version(2).build().unwrap();
+        assert!(collector.insert(b"one", b"1").is_err());
+    }
+
+    #[test]
+    fn perfect_hash_lookups() {
+        let mut collector = Collector::builder().perfect_hash(true).heap_threshold(32).max_size(64).build().unwrap();
+        let blocks: Vec<String> = (0..2000).map(|n| format!("block {n} {}", "x".repeat(n % 40))).collect();
+        for block in blocks.iter() {
+            collector.add(block).unwrap();
+        }
+        let mut output = tempfile().unwrap();
+        collector.press(&mut output).unwrap();
+
+        let reader = Reader::from_file(&output).unwrap();
+        reader.verify().unwrap();
+        assert!(reader.header().optional_features().contains(Features::PERFECT_HASH));
+        assert!(reader.shelves().all(|shelf| shelf.index.is_some()));
+        for block in blocks.iter() {
+            let shelf = reader.shelves_for(block.len()).next().unwrap();
+            let index = reader.find(block).unwrap();
+            assert_eq!(shelf.get(index).as_deref(), Some(block.as_bytes()));
+        }
+        assert!(!reader.contains("block 5000"));
+        assert!(!reader.contains("missing from the heap and long enough"));
+        assert!(Collector::builder().version(2).perfect_hash(true).build().is_err());
+    }
+
+    #[test]
+    fn filtered_lookups() {
+        let mut collector = Collector::builder().filter(0.01).heap_threshold(8).build().unwrap();
+        for n in 0..1000 {
+            collector.add(format!("{n:04}")).unwrap();
+            collector.add(format!("long block {n}")).unwrap();
+        }
+        let mut output = tempfile().unwrap();
+        collector.press(&mut output).unwrap();
+
+        let reader = Reader::from_file(&output).unwrap();
+        reader.verify().unwrap();
+        assert!(reader.header().optional_features().contains(Features::FILTER));
+        assert!(reader.shelves().all(|shelf| shelf.filter_rate() == Some(0.01)));
+        assert!((0..1000).all(|n| reader.contains(format!("{n:04}")) && reader.contains(format!("long block {n}"))));
+        let shelf = reader.shelf(4).unwrap();
+        let filter = shelf.filter.unwrap();
+        let passed = (1000..9999)
+            .map(|n| format!("{n:04}"))
+            .filter(|block| filter.may_contain(filter::key(hash::hash(reader.seed(), block.as_bytes()), filter.seed(), block.as_bytes())))
+            .count();
+        assert!(passed < 200, "{passed} false positives");
+        assert!((1000..9999).all(|n| !reader.contains(format!("{n:04}"))));
+
+        assert!(Collector::builder().filter(0.0).build().is_err());
+        assert!(Collector::builder().filter(f64::NAN).build().is_err());
+        assert!(Collector::builder().version(2).filter(0.01).build().is_err());
+    }
+
+    #[test]
+    #[cfg(any(feature = "lz4", feature = "zstd"))]
+    fn compressed_shelves() {
+        let blocks: Vec<String> = (0..3000).map(|n| format!("{:>12}{}", n % 700, "-".repeat(n % 3 * 20))).collect();
+        for codec in [Codec::Lz4, Codec::Zstd].into_iter().filter(Codec::is_available) {
+            for perfect_hash in [false, true] {
+                let mut collector = Collector::builder()
+                    .compression(codec)
+                    .chunk_size(512)
+                    .heap_threshold(30)
+                    .max_size(64)
+                    .perfect_hash(perfect_hash)
+                    .dedup(false)
+                    .build()
+                    .unwrap();
+                for block in blocks.iter() {
+                    collector.add(block).unwrap();
+                }
+                let mut output = tempfile().unwrap();
+                let written = collector.press(&mut output).unwrap();
+
+                let reader = Reader::from_file(&output).unwrap();
+                reader.verify().unwrap();
+                assert!(reader.header().required_features().contains(codec.feature()));
+                assert!(reader.shelves().all(|shelf| shelf.codec() == Some(codec)));
+                let mut plain = Collector::builder().heap_threshold(30).max_size(64).perfect_hash(perfect_hash).dedup(false).build().unwrap();
+                for block in blocks.iter() {
+                    plain.add(block).unwrap();
+                }
+                let plain_size = plain.press(&mut tempfile().unwrap()).unwrap();
+                assert!(written < plain_size * 3 / 4, "{written} compressed, {plain_size} plain");
+                for block in blocks.iter() {
+                    let index = reader.find(block).unwrap();
+                    let shelf = reader.shelf(block.len()).or_else(|| reader.heap()).unwrap();
+                    assert_eq!(shelf.get_checked(index).unwrap().as_deref(), Some(block.as_bytes()));
+                }
+                assert!(!reader.contains(format!("{:>12}", 700)));
+                assert!(!reader.contains(format!("{:>12}{}", 0, "-".repeat(30))));
+                let mut expected: Vec<&[u8]> = blocks.iter().map(String::as_bytes).collect();
+                let mut pressed: Vec<Vec<u8>> = reader.shelves().flat_map(|shelf| shelf.blocks().map(Cow::into_owned)).collect();
+                expected.sort();
+                pressed.sort();
+                assert_eq!(pressed, expected);
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(any(feature = "lz4", feature = "zstd"))]
+    fn compressed_counts_are_checked() {
+        let codec = [Codec::Lz4, Codec::Zstd].into_iter().find(Codec::is_available).unwrap();
+        let mut collector = Collector::builder().compression(codec).build().unwrap();
+        for n in 0..100 {
+            collector.add(format!("{:>8}", n % 10)).unwrap();
+            collector.add(format!("{n:>8}")).unwrap();
+        }
+        let mut pressed = Vec::new();
+        collector.press(&mut pressed).unwrap();
+
+        // A forged entry count with every checksum in order, and chunk index entries and counts to go with it
+        let forge = |count: u64, chunk_entries: u64, chunk_count: u64| {
+            forge_chunk_index(&pressed, |desc, index| {
+                desc.count = count;
+                index[8..16].copy_from_slice(&chunk_entries.to_le_bytes());
+                index[16..24].copy_from_slice(&chunk_count.to_le_bytes());
+            })
+        };
+        // The 100 blocks fit in a single chunk
+        assert_eq!(forge(100, 100, 1).unwrap().shelves().next().unwrap().len(), 100);
+        assert!(forge(1 << 62, 1, 1 << 62).is_err());
+        assert!(forge(1 << 62, 1 << 62, 1).is_err());
+        assert!(forge(101, 101, 1).is_err());
+    }
+
+    #[test]
+    #[cfg(any(feature = "lz4", feature = "zstd"))]
+    fn chunk_sizes_are_bounded() {
+        let codec = [Codec::Lz4, Codec::Zstd].into_iter().find(Codec::is_available).unwrap();
+        for builder in [Collector::builder(), Collector::builder().heap_threshold(1)] {
+            let mut collector = builder.compression(codec).build().unwrap();
+            for n in 0..100 {
+                collector.add(format!("{n:>8}")).unwrap();
+            }
+            let mut pressed = Vec::new();
+            collector.press(&mut pressed).unwrap();
+            assert!(forge_chunk_index(&pressed, |_, _| {}).is_ok());
+            // The first chunk's decompressed size, past what its entries can take
+            let oversized = forge_chunk_index(&pressed, |_, index| index[40..48].copy_from_slice(&(1u64 << 40).to_le_bytes()));
+            assert!(oversized.is_err());
+
+            // Without the checksums fixed up, a changed index is caught on open
+            let (_, start) = chunk_index_at(&pressed);
+            pressed[start + 40] ^= 1;
+            let mut file = tempfile().unwrap();
+            file.write_all(&pressed).unwrap();
+            assert!(matches!(Reader::from_file(&file), Err(Error::ExtensionChecksum { run: 0, .. })));
+        }
+    }
+
+    /// Descriptor of the first run in `buf` and where its chunk index starts
+    #[cfg(any(feature = "lz4", feature = "zstd"))]
+    fn chunk_index_at(buf: &[u8]) -> (RunDesc, usize) {
+        let header = Header::from_buf(buf).unwrap();
+        let desc = RunDesc::from_buf_at(buf, header.size(), header.version()).unwrap();
+        let extension = &buf[desc.extension_offset()..desc.extension_offset() + desc.extension_size()];
+        let (_, payload) = Extensions::new(extension).find(|record| matches!(record, Ok((Extensions::CHUNKS, _)))).unwrap().unwrap();
+        (desc, payload.as_ptr() as usize - buf.as_ptr() as usize)
+    }
+
+    /// Open `pressed` with the first run's descriptor and chunk index changed by `edit`, every checksum fixed up
+    #[cfg(any(feature = "lz4", feature = "zstd"))]
+    fn forge_chunk_index(pressed: &[u8], edit: impl FnOnce(&mut RunDesc, &mut [u8])) -> Result<Reader> {
+        let mut buf = pressed.to_vec();
+        let mut header = Header::from_buf(&buf).unwrap();
+        let version = header.version();
+        let (mut desc, start) = chunk_index_at(&buf);
+        let extension = desc.extension_offset()..desc.extension_offset() + desc.extension_size();
+        edit(&mut desc, &mut buf[start..extension.end]);
+        desc.ext_checksum = hash::checksum(&buf[extension]);
+        let mut table = Vec::new();
+        desc.write_out(&mut table, version).unwrap();
+        header.table_checksum = hash::checksum(&table);
+        let mut head = Vec::new();
+        header.write_out(&mut head).unwrap();
+        head.extend_from_slice(&table);
+        buf[..head.len()].copy_from_slice(&head);
+        let mut file = tempfile().unwrap();
+        file.write_all(&buf).unwrap();
+        Reader::from_file(&file)
+    }
+
+    #[test]
+    fn unavailable_codec() {
+        for codec in [Codec::Lz4, Codec::Zstd].into_iter().filter(|codec| !codec.is_available()) {
+            let result = Collector::builder().compression(codec).build();
+            assert!(matches!(result, Err(Error::UnsupportedFeatures(_))));
+        }
+        assert!(Collector::builder().version(2).compression(Codec::Lz4).build().is_err());
+    }
+
+    #[test]
+    fn verify_clean_file() {
+        let mut collector = Collector::builder().alignment(16).heap_threshold(5).build().unwrap();
+        for block in [&b"abc"[..], b"defg", b"hijkl", b"mnopqrs"] {
+            collector.add(block).unwrap();
+        }
+        let mut output = tempfile().unwrap();
+        collector.press(&mut output).unwrap();
+        Reader::from_file(&output).unwrap().verify().unwrap();
+    }
+}
@@ -0,0 +1,388 @@
+//! Collecting more blocks than fit in memory
+//!
+//! A [`SpillingCollector`] buffers blocks in a [`Collector`] until they take
+//! more than its memory budget, then writes every buffered shelf out to a
+//! temporary file as a sorted run and starts over. Whenever [`FAN_IN`] spill
+//! files of the same level pile up, they are merged into one of the next
+//! level, so the files kept open grow with the logarithm of the spills.
+//! Pressing merges the runs of each shelf with the blocks still buffered,
+//! dropping duplicates on the way, and encodes the merged shelf through
+//! temporary files as well.
+//!
+//! A spill file holds the runs of its shelves back to back. A run is a series
+//! of records, each the block hash as a little-endian `u32`, the block length
+//! as a `u64`, the value length plus one as a `u64`, zero for a block added
+//! without a value, then the block and value bytes.
+
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet},
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Take, Write},
+    mem::size_of,
+    ops::Range,
+    path::PathBuf,
+};
+
+use crate::{
+    merge::{Entry, Merge},
+    spool::{self, ShelfWriter},
+    to_usize, Added, Collector, Decoder, Result, RunKind,
+};
+
+/// Size of the fixed fields of a run record
+const RECORD_FIELDS_SIZE: usize = size_of::<u32>() + 2 * size_of::<u64>();
+
+/// Rough bookkeeping cost of a buffered block beyond its bytes, the block itself and its dedup entry
+const BLOCK_OVERHEAD: usize = 64;
+
+/// Spill files of one level merged into one of the next
+const FAN_IN: usize = 16;
+
+/// Sources of a shelf merge, oldest first
+type Sources<'a> = Vec<Box<dyn Iterator<Item = Result<Entry<'a>>> + 'a>>;
+
+/// Collector for sets larger than memory, built by [`crate::CollectorBuilder::build_spilling`]
+///
+/// Blocks are buffered until they take about the memory budget, then sorted
+/// and written out to temporary files, which [`SpillingCollector::press`]
+/// merges. The pressed file is the same as the one a [`Collector`] with the
+/// same settings would press from the same blocks.
+#[derive(Debug)]
+pub struct SpillingCollector {
+    // Buffered blocks, and the settings everything is pressed with
+    collector: Collector,
+    memory_budget: usize,
+    // Approximate memory taken by the buffered blocks
+    buffered: usize,
+    dir: Option<PathBuf>,
+    // Spill files oldest first, their levels never increase along the way
+    spills: Vec<Spill>,
+    // Number of spills so far, merged or not
+    spilled: usize,
+}
+
+/// Temporary file holding sorted runs of the shelves, spilled or merged at once
+#[derive(Debug)]
+struct Spill {
+    file: File,
+    // Byte range of every shelf's run in the file
+    runs: BTreeMap<(RunKind, usize), Range<u64>>,
+    // Number of merges the blocks went through
+    level: u32,
+}
+
+impl Spill {
+    /// Reader of the run of the shelf of `kind` and `block_size`, if the spill holds one
+    ///
+    /// Readers of the same spill share its file position, only one can be read at a time.
+    fn run(&self, kind: RunKind, block_size: usize) -> Result<Option<RunReader<'_>>> {
+        self.runs.get(&(kind, block_size)).map(|range| RunReader::new(&self.file, range.clone())).transpose()
+    }
+}
+
+impl SpillingCollector {
+    pub(crate) fn new(collector: Collector, memory_budget: usize, dir: Option<PathBuf>) -> Self {
+        Self {
+            collector,
+            memory_budget,
+            buffered: 0,
+            dir,
+            spills: Vec::new(),
+            spilled: 0,
+        }
+    }
+
+    /// Add a block, duplicates are only dropped when pressing
+    pub fn add<T: AsRef<[u8]>>(&mut self, data: T) -> Result<()> {
+        let data = data.as_ref();
+        // A duplicate of a buffered block takes no more room
+        if let Added::New(_) = self.collector.add(data)? {
+            self.buffered += data.len() + BLOCK_OVERHEAD;
+        }
+        self.spill_over_budget()
+    }
+
+    /// Add `key` with `value` attached, see [`Collector::insert`]
+    pub fn insert<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, value: V) -> Result<()> {
+        let (key, value) = (key.as_ref(), value.as_ref());
+        // A buffered key only gets its value replaced
+        self.buffered += match self.collector.insert(key, value)? {
+            Added::New(_) => key.len() + value.len() + BLOCK_OVERHEAD,
+            Added::Duplicate(_) => value.len(),
+        };
+        self.spill_over_budget()
+    }
+
+    fn spill_over_budget(&mut self) -> Result<()> {
+        if self.buffered > self.memory_budget {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    /// Write the buffered blocks out to a spill file, one sorted run per shelf
+    pub fn spill(&mut self) -> Result<()> {
+        if !self.collector.is_empty() {
+            let mut writer = BufWriter::new(spool::temp_file(self.dir.as_deref())?);
+            let mut runs = BTreeMap::new();
+            let mut offset = 0;
+            for shelf in self.collector.runs() {
+                let start = offset;
+                for block in shelf.sorted_blocks() {
+                    offset += write_record(&mut writer, block.hash, &block.data, block.value.as_deref())?;
+                }
+                runs.insert((shelf.kind, shelf.block_size), start..offset);
+            }
+            self.spills.push(Spill {
+                file: spool::into_file(writer)?,
+                runs,
+                level: 0,
+            });
+            self.spilled += 1;
+            self.merge_full_levels()?;
+        }
+        self.collector.clear();
+        self.buffered = 0;
+        Ok(())
+    }
+
+    /// Merge the newest spills into one as long as [`FAN_IN`] of them share a level
+    fn merge_full_levels(&mut self) -> Result<()> {
+        while let Some(start) = self.spills.len().checked_sub(FAN_IN) {
+            let level = self.spills[start].level;
+            if self.spills[start..].iter().any(|spill| spill.level != level) {
+                break;
+            }
+            let merged = self.merge_spills(&self.spills[start..], level + 1)?;
+            self.spills.truncate(start);
+            self.spills.push(merged);
+        }
+        Ok(())
+    }
+
+    /// Merge `spills` into a new one at `level`, dropping duplicates like pressing does
+    fn merge_spills(&self, spills: &[Spill], level: u32) -> Result<Spill> {
+        let keys: BTreeSet<(RunKind, usize)> = spills.iter().flat_map(|spill| spill.runs.keys().copied()).collect();
+        let mut writer = BufWriter::new(spool::temp_file(self.dir.as_deref())?);
+        let mut runs = BTreeMap::new();
+        let mut offset = 0;
+        for (kind, block_size) in keys {
+            let start = offset;
+            for merged in Merge::new(spill_sources(spills, kind, block_size)?, self.collector.dedup)? {
+                let entry = merged?.entry;
+                offset += write_record(&mut writer, entry.hash, &entry.data, entry.value.as_deref())?;
+            }
+            runs.insert((kind, block_size), start..offset);
+        }
+        Ok(Spill {
+            file: spool::into_file(writer)?,
+            runs,
+            level,
+        })
+    }
+
+    /// Longest block the collector takes, see [`crate::CollectorBuilder::max_size`]
+    pub fn max_size(&self) -> usize {
+        self.collector.max_size()
+    }
+
+    /// Number of times the buffered blocks were written to temporary files so far
+    pub fn spilled_runs(&self) -> usize {
+        self.spilled
+    }
+
+    /// Merge the spilled runs and the buffered blocks into a pressed file,
+    /// returning the number of bytes written
+    pub fn press<F: Write>(&self, writer: &mut F) -> Result<usize> {
+        let mut keys: BTreeSet<(RunKind, usize)> = self.spills.iter().flat_map(|spill| spill.runs.keys().copied()).collect();
+        keys.extend(self.collector.runs().map(|shelf| (shelf.kind, shelf.block_size)));
+        let mut shelves = Vec::new();
+        for (kind, block_size) in keys {
+            let mut sources = spill_sources(&self.spills, kind, block_size)?;
+            // The buffered blocks are the newest
+            if let Some(shelf) = self.collector.shelf(kind, block_size) {
+                sources.push(Box::new(shelf.sorted_blocks().into_iter().map(|block| {
+                    Ok(Entry {
+                        hash: block.hash,
+                        data: Cow::Borrowed(&block.data),
+                        value: block.value.as_deref().map(Cow::Borrowed),
+                    })
+                })));
+            }
+            let mut shelf = ShelfWriter::new(&self.collector, kind, block_size, self.dir.as_deref())?;
+            for merged in Merge::new(sources, self.collector.dedup)? {
+                let entry = merged?.entry;
+                shelf.push(entry.hash, &entry.data, entry.value.as_deref().unwrap_or_default())?;
+            }
+            shelves.extend(shelf.finish()?);
+        }
+        self.collector.write_shelves(&shelves, writer)
+    }
+}
+
+/// Runs of the shelf of `kind` and `block_size` in `spills`, oldest first
+fn spill_sources<'a>(spills: &'a [Spill], kind: RunKind, block_size: usize) -> Result<Sources<'a>> {
+    let mut sources: Sources<'a> = Vec::new();
+    for spill in spills {
+        if let Some(run) = spill.run(kind, block_size)? {
+            sources.push(Box::new(run));
+        }
+    }
+    Ok(sources)
+}
+
+/// Write a run record, returning its size
+fn write_record<W: Write>(writer: &mut W, hash: u32, data: &[u8], value: Option<&[u8]>) -> Result<u64> {
+    writer.write_all(&hash.to_le_bytes())?;
+    writer.write_all(&(data.len() as u64).to_le_bytes())?;
+    let value_length = value.map_or(0, |value| value.len() as u64 + 1);
+    writer.write_all(&value_length.to_le_bytes())?;
+    writer.write_all(data)?;
+    writer.write_all(value.unwrap_or_default())?;
+    Ok((RECORD_FIELDS_SIZE + data.len() + value.map_or(0, <[u8]>::len)) as u64)
+}
+
+/// Reads the blocks of a run back in order
+struct RunReader<'a> {
+    reader: BufReader<Take<&'a File>>,
+}
+
+impl<'a> RunReader<'a> {
+    /// Reader of the run in `range` of `file`
+    fn new(mut file: &'a File, range: Range<u64>) -> Result<Self> {
+        file.seek(SeekFrom::Start(range.start))?;
+        Ok(Self {
+            reader: BufReader::new(file.take(range.end - range.start)),
+        })
+    }
+
+    fn record(&mut self) -> Result<Option<Entry<'a>>> {
+        if self.reader.fill_buf()?.is_empty() {
+            return Ok(None);
+        }
+        let mut fields = [0; RECORD_FIELDS_SIZE];
+        self.reader.read_exact(&mut fields)?;
+        let mut decoder = Decoder::new(&fields);
+        let hash = decoder.u32()?;
+        let mut data = vec![0; to_usize(decoder.u64()?)?];
+        let value_length = to_usize(decoder.u64()?)?;
+        self.reader.read_exact(&mut data)?;
+        let value = match value_length.checked_sub(1) {
+            Some(length) => {
+                let mut value = vec![0; length];
+                self.reader.read_exact(&mut value)?;
+                Some(value)
+            }
+            None => None,
+        };
+        Ok(Some(Entry {
+            hash,
+            data: Cow::Owned(data),
+            value: value.map(Cow::Owned),
+        }))
+    }
+}
+
+impl<'a> Iterator for RunReader<'a> {
+    type Item = Result<Entry<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.record().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Codec, CollectorBuilder, Reader};
+    use rand::{rngs::SmallRng, Rng, SeedableRng};
+    use tempfile::tempfile;
+
+    /// Press the same additions with a collector and a spilling collector
+    fn press_both(builder: CollectorBuilder, additions: &[(Vec<u8>, Option<Vec<u8>>)]) -> (Vec<u8>, Vec<u8>, usize) {
+        let mut collector = builder.clone().build().unwrap();
+        let mut spilling = builder.build_spilling(2000).unwrap();
+        for (block, value) in additions {
+            match value {
+                Some(value) => {
+                    collector.insert(block, value).unwrap();
+                    spilling.insert(block, value).unwrap();
+                }
+                None => {
+                    collector.add(block).unwrap();
+                    spilling.add(block).unwrap();
+                }
+            }
+        }
+        let (mut expected, mut pressed) = (Vec::new(), Vec::new());
+        collector.press(&mut expected).unwrap();
+        spilling.press(&mut pressed).unwrap();
+        (expected, pressed, spilling.spilled_runs())
+    }
+
+    #[test]
+    fn matches_in_memory_press() {
+        let mut rng = SmallRng::seed_from_u64(18);
+        let mut additions = Vec::new();
+        for _ in 0..3000 {
+            let length = rng.gen_range(1..40);
+            // Few distinct blocks so duplicates land in different runs
+            let block: Vec<u8> = (0..length).map(|_| rng.gen_range(b'a'..b'e')).collect();
+            let value = rng.gen_bool(0.5).then(|| rng.gen::<[u8; 3]>().to_vec());
+            additions.push((block, value));
+        }
+        let plain: Vec<_> = additions.iter().map(|(block, _)| (block.clone(), None)).collect();
+
+        let mut builders = vec![
+            Collector::builder(),
+            Collector::builder().dedup(false).heap_threshold(20),
+            Collector::builder().heap_threshold(10).perfect_hash(true).filter(0.01).alignment(16),
+        ];
+        builders.extend(
+            [Codec::Lz4, Codec::Zstd]
+                .into_iter()
+                .filter(Codec::is_available)
+                .map(|codec| Collector::builder().heap_threshold(10).compression(codec).chunk_size(100)),
+        );
+        for builder in builders {
+            for additions in [&plain, &additions] {
+                let (expected, pressed, runs) = press_both(builder.clone(), additions);
+                assert!(runs > 10);
+                assert!(expected == pressed, "{builder:?}");
+            }
+        }
+
+        let (_, pressed, _) = press_both(Collector::builder().heap_threshold(10), &additions);
+        let mut file = tempfile().unwrap();
+        file.write_all(&pressed).unwrap();
+        let reader = Reader::from_file(&file).unwrap();
+        reader.verify().unwrap();
+        // The last value inserted for a key wins, adding it again keeps it
+        for (block, _) in additions.iter() {
+            let value = additions.iter().rev().find(|(other, value)| other == block && value.is_some());
+            let expected = value.and_then(|(_, value)| value.as_deref()).unwrap_or_default();
+            assert_eq!(reader.get(block), Some(expected));
+        }
+    }
+
+    #[test]
+    fn spill_files_stay_few() {
+        let mut collector = Collector::builder().heap_threshold(30).build().unwrap();
+        let mut spilling = Collector::builder().heap_threshold(30).build_spilling(4096).unwrap();
+        for n in 0..20_000 {
+            let block = format!("{n}{}", "-".repeat(n % 35));
+            collector.insert(&block, n.to_string()).unwrap();
+            spilling.insert(&block, n.to_string()).unwrap();
+            // Duplicates don't fill the buffer
+            spilling.add(&block).unwrap();
+        }
+        assert!(spilling.spilled_runs() > 300);
+        // One file per spill, merged sixteen at a time
+        assert!(spilling.spills.len() < 3 * FAN_IN, "{} spill files", spilling.spills.len());
+        let (mut expected, mut pressed) = (Vec::new(), Vec::new());
+        collector.press(&mut expected).unwrap();
+        spilling.press(&mut pressed).unwrap();
+        assert!(expected == pressed);
+    }
+}
@@ -0,0 +1,158 @@
+//! Encoding shelves from streams of blocks
+//!
+//! [`Collector::press`] sorts and encodes a shelf in memory. A [`ShelfWriter`]
+//! takes the blocks of a shelf one at a time, already in on-disk order, and
+//! spools the bulk region and value table to temporary files, so the blocks
+//! never need to fit in memory together. The perfect hash index and filter
+//! still keep 4 and 8 bytes per entry in memory while they're gathered.
+//! Finished regions are mapped back in and written out like in-memory ones,
+//! the output is the same as pressing the blocks with the collector.
+
+use std::{
+    fs::File,
+    io::{self, BufWriter, Seek, Write},
+    path::Path,
+};
+
+use crate::{
+    compress,
+    extension::{self, Extensions},
+    Collector, EncodedShelf, Error, HeapEntry, Indexes, Region, Result, RunKind,
+};
+
+/// Create an unnamed temporary file in `dir`, or the system's temporary directory
+pub(crate) fn temp_file(dir: Option<&Path>) -> Result<File> {
+    Ok(match dir {
+        Some(dir) => tempfile::tempfile_in(dir)?,
+        None => tempfile::tempfile()?,
+    })
+}
+
+/// Flush `writer` and rewind its file to the start
+pub(crate) fn into_file(writer: BufWriter<File>) -> Result<File> {
+    let mut file = writer.into_inner().map_err(io::IntoInnerError::into_error)?;
+    file.rewind()?;
+    Ok(file)
+}
+
+/// Encodes one shelf from its blocks in on-disk order
+pub(crate) struct ShelfWriter<'c> {
+    collector: &'c Collector,
+    dir: Option<&'c Path>,
+    kind: RunKind,
+    block_size: usize,
+    count: usize,
+    // Fixed entries, or heap index entries with the block data in heap_data
+    entries: BufWriter<File>,
+    heap_data: Option<BufWriter<File>>,
+    data_offset: u64,
+    // Value table offsets and data, if the collector holds values
+    values: Option<(BufWriter<File>, BufWriter<File>)>,
+    value_offset: u64,
+    indexes: Indexes,
+}
+
+impl<'c> ShelfWriter<'c> {
+    /// Writer for the shelf of `kind` and `block_size`, encoded with the settings of `collector`
+    pub fn new(collector: &'c Collector, kind: RunKind, block_size: usize, dir: Option<&'c Path>) -> Result<Self> {
+        let spool = || -> Result<BufWriter<File>> { Ok(BufWriter::new(temp_file(dir)?)) };
+        let values = if collector.values {
+            let mut offsets = spool()?;
+            offsets.write_all(&0u64.to_le_bytes())?;
+            Some((offsets, spool()?))
+        } else {
+            None
+        };
+        Ok(Self {
+            collector,
+            dir,
+            kind,
+            block_size,
+            count: 0,
+            entries: spool()?,
+            heap_data: if kind == RunKind::Heap { Some(spool()?) } else { None },
+            data_offset: 0,
+            values,
+            value_offset: 0,
+            indexes: Indexes::new(collector),
+        })
+    }
+
+    /// Append the next block, blocks must come sorted by hash, then bytes, then value
+    pub fn push(&mut self, hash: u32, data: &[u8], value: &[u8]) -> Result<()> {
+        let length = data.len() as u64;
+        match (self.kind, self.heap_data.as_mut()) {
+            (RunKind::Heap, Some(heap_data)) if data.len() >= self.block_size => {
+                let entry = HeapEntry {
+                    hash,
+                    offset: self.data_offset,
+                    length,
+                };
+                self.data_offset = self.data_offset.checked_add(length).ok_or(Error::Overflow(self.data_offset))?;
+                entry.write_out(&mut self.entries, self.collector.version)?;
+                heap_data.write_all(data)?;
+            }
+            (RunKind::Fixed, _) if data.len() == self.block_size => {
+                self.entries.write_all(&hash.to_le_bytes())?;
+                self.entries.write_all(data)?;
+            }
+            _ => {
+                return Err(format!("Block of {} bytes doesn't belong on the {:?} shelf for {} bytes", data.len(), self.kind, self.block_size).into());
+            }
+        }
+        if let Some((offsets, value_data)) = self.values.as_mut() {
+            value_data.write_all(value)?;
+            self.value_offset += value.len() as u64;
+            offsets.write_all(&self.value_offset.to_le_bytes())?;
+        }
+        self.indexes.push(hash, data);
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Finish the regions, None if no block was pushed
+    pub fn finish(self) -> Result<Option<EncodedShelf>> {
+        if self.count == 0 {
+            return Ok(None);
+        }
+        let version = self.collector.version;
+        let mut bulk = into_file(self.entries)?;
+        if let Some(heap_data) = self.heap_data {
+            bulk.seek(io::SeekFrom::End(0))?;
+            io::copy(&mut into_file(heap_data)?, &mut bulk)?;
+        }
+        let mut bulk = Region::from_file(&bulk)?;
+
+        // Records go in the same order as in Shelf::encode
+        let mut extension = BufWriter::new(temp_file(self.dir)?);
+        if let Some((offsets, value_data)) = self.values {
+            let (mut offsets, mut value_data) = (into_file(offsets)?, into_file(value_data)?);
+            let length = offsets.metadata()?.len() + value_data.metadata()?.len();
+            extension::write_record_header(&mut extension, Extensions::VALUES, length)?;
+            io::copy(&mut offsets, &mut extension)?;
+            io::copy(&mut value_data, &mut extension)?;
+        }
+        for (tag, payload) in self.indexes.records()? {
+            extension::write_record(&mut extension, tag, &payload)?;
+        }
+        let mut codec = None;
+        if let Some(compression) = self.collector.compression {
+            // Kept only if it shrinks, as in Shelf::encode
+            let mut chunks = BufWriter::new(temp_file(self.dir)?);
+            let (size, index) = compress::compress_run(self.kind, self.count, &bulk, compression, version, &mut chunks)?;
+            if size < bulk.len() as u64 {
+                bulk = Region::from_file(&into_file(chunks)?)?;
+                extension::write_record(&mut extension, Extensions::CHUNKS, &index)?;
+                codec = Some(compression.codec);
+            }
+        }
+        Ok(Some(EncodedShelf {
+            kind: self.kind,
+            block_size: self.block_size,
+            count: self.count,
+            bulk,
+            extension: Region::from_file(&into_file(extension)?)?,
+            codec,
+        }))
+    }
+}
@@ -0,0 +1,64 @@
+use std::io::Write;
+
+use crate::{CollectorBuilder, Features, Header, Reader, Result};
+
+/// Press the blocks of `reader` again in the newest format version
+///
+/// The settings [`CollectorBuilder::from_reader`] finds in the old file carry
+/// over but the version, so the rewritten file holds the same shelves, and so
+/// do the values of a key/value file. The shelves appended to an appendable
+/// file are pressed together. Other extension records are rebuilt from the
+/// blocks. Returns the number of bytes written.
+pub fn upgrade<W: Write>(reader: &Reader, writer: &mut W) -> Result<usize> {
+    let header = reader.header();
+    let mut collector = CollectorBuilder::from_reader(reader)?.version(Header::VERSION).build()?;
+    let values = header.optional_features().contains(Features::VALUES);
+    for shelf in reader.shelves() {
+        for index in 0..shelf.len() {
+            let block = shelf.get_checked(index)?.ok_or_else(|| shelf.outside_run(index))?;
+            match shelf.value(index) {
+                Some(value) if values => collector.insert(block, value)?,
+                _ => collector.add(block)?,
+            };
+        }
+    }
+    collector.press(writer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Collector;
+    use tempfile::tempfile;
+
+    #[test]
+    fn upgrade_version_1() {
+        let mut collector = Collector::builder()
+            .version(1)
+            .seed(42)
+            .alignment(8)
+            .heap_threshold(6)
+            .build()
+            .unwrap();
+        for block in ["a", "bb", "cc", "a much longer block", "sixsix"] {
+            collector.add(block).unwrap();
+        }
+        let mut old = tempfile().unwrap();
+        collector.press(&mut old).unwrap();
+        let old = Reader::from_file(&old).unwrap();
+        assert_eq!(old.header().version(), 1);
+
+        let mut new = tempfile().unwrap();
+        upgrade(&old, &mut new).unwrap();
+        let new = Reader::from_file(&new).unwrap();
+        new.verify().unwrap();
+        assert_eq!(new.header().version(), Header::VERSION);
+        assert_eq!(new.seed(), 42);
+        assert_eq!(new.header().alignment(), 8);
+        assert_eq!(new.heap().unwrap().block_size(), 6);
+        for (old_shelf, new_shelf) in old.shelves().zip(new.shelves()) {
+            assert_eq!(old_shelf.blocks().collect::<Vec<_>>(), new_shelf.blocks().collect::<Vec<_>>());
+        }
+        assert_eq!(old.shelves().count(), new.shelves().count());
+    }
+}